@@ -7,21 +7,23 @@ use cgmath::*;
 use graphics::rasterizer::*;
 use graphics::geometry::*;
 use graphics::sdl_utils::*;
-use sdl2::{
-    event::Event,
-    pixels::Color,
-};
+use sdl2::event::Event;
+use graphics::colors::*;
 use graphics::frame::*;
 use graphics::textures::*;
 use graphics::render::*;
 use graphics::camera::*;
+use graphics::controllers::*;
+use graphics::input::*;
 use graphics::materials::*;
+use graphics::shaders::*;
+use graphics::targets::*;
 
 fn main() {
     let ctx = sdl2::init().unwrap();
     let mut events = ctx.event_pump().unwrap();
     let canvas = create_sdl_canvas(&ctx, 1000, 800);
-    let rasterizer = Rasterizer::create(1000, 800);
+    let rasterizer = Rasterizer::with_antialiasing(1000, 800, Antialiasing::Multisample(Msaa::X4));
     let mut renderer = Renderer::new(rasterizer, canvas);
 
     let mut texture_frame = Frame::new(
@@ -45,42 +47,91 @@ fn main() {
         1000.0,
         Matrix4::from_translation(Vector3{x: 0.0, y: 0.6, z: 2.0}),
     );
+    let mut controller = OrbitController::new(Vector3{x: 0.0, y: 0.3, z: 0.0}, 2.0);
+    controller.pitch = Deg(10.0).into();
+    let mut input = InputState::new();
+    controller.update(&input, &mut camera);
     renderer.set_from_camera(&camera);
+    renderer.lighting.lights[0].casts_shadows = true;
 
     let mut checkered_material = Material::new();
     checkered_material.texture = Some(0);
     let mut default_material = Material::new();
-    let mesh = Mesh::xy_face(2.5)
-        .transformed(Matrix4::from_angle_x(Deg(-90.0)));
-    let mut mesh2 = Mesh::sphere(0.5, 5)
-        .transformed(Matrix4::from_translation(Vector3{x: 0.0, y: 0.5, z: 0.0}));
+    let floor = Mesh::xy_face(2.5);
+    let floor_transform = Matrix4::from_angle_x(Deg(-90.0));
+    let sphere = Mesh::sphere(0.5, 5);
+    let (floor_bounds, sphere_bounds) = (floor.bounds(), sphere.bounds());
+    let mut sphere_angle = 0.0;
+
+    // A monitor behind the floor showing the scene from above. The face's v runs upwards, but
+    // the top of the rendered view is at v = 0.
+    let mut monitor = Mesh::xy_face(1.0);
+    for triangle in monitor.vertices.iter_mut() {
+        for vertex in vec![&mut triangle.0, &mut triangle.1, &mut triangle.2] {
+            vertex.uv.y = 1.0 - vertex.uv.y;
+        }
+    }
+    let monitor_transform = Matrix4::from_translation(Vector3{x: 1.0, y: 1.2, z: -1.5});
+    let mut monitor_target = RenderTarget::new(128, 128);
+    let mut monitor_texture = monitor_target.to_texture();
+    let overhead_camera = Camera::create(
+        70.0,
+        1.0,
+        0.1,
+        100.0,
+        Matrix4::from_translation(Vector3{x: 0.0, y: 2.5, z: 0.0}) * Matrix4::from_angle_x(Deg(-90.0)),
+    );
 
     'main: loop {
 
+        let sphere_transform = Matrix4::from_translation(Vector3{x: 0.0, y: 0.5, z: 0.0})
+            * Matrix4::from_angle_y(Deg(sphere_angle));
+        renderer.render_shadow_maps(&[(&floor, floor_transform), (&sphere, sphere_transform)]);
+        renderer.render_to(&mut monitor_target, |renderer| {
+            renderer.set_from_camera(&overhead_camera);
+            renderer.set_material(checkered_material);
+            renderer.mesh_with_bounds(&floor, floor_bounds, floor_transform);
+            renderer.set_material(default_material);
+            renderer.mesh_with_bounds(&sphere, sphere_bounds, sphere_transform);
+        });
+        // The screen glows rather than being lit.
+        monitor_target.update_texture(&mut monitor_texture);
+        let screen_shader = |fragment: &Fragment<StandardVaryings>| FloatColor::from_color(&monitor_texture.sample_with_derivatives(
+            fragment.varyings.uv,
+            fragment.ddx.uv,
+            fragment.ddy.uv,
+            TextureFilterMode::Trilinear,
+        ));
+        let vertex_shader = StandardVertexShader::with_model(renderer.world_to_clip_matrix(), monitor_transform);
+        renderer.draw_mesh(&monitor, &vertex_shader, &screen_shader);
+
         renderer.set_material(checkered_material);
-        renderer.mesh(&mesh);
+        renderer.mesh_with_bounds(&floor, floor_bounds, floor_transform);
         renderer.set_material(default_material);
-        renderer.mesh(&mesh2);
+        renderer.mesh_with_bounds(&sphere, sphere_bounds, sphere_transform);
 
-        renderer.present();
+        // World axes at the corner of the floor.
+        let corner = Vector3{x: -1.2, y: 0.01, z: -1.2};
+        renderer.line(corner, corner + Vector3::unit_x() * 0.3, FloatColor::from_rgb(1.0, 0.0, 0.0), 2.0);
+        renderer.line(corner, corner + Vector3::unit_y() * 0.3, FloatColor::from_rgb(0.0, 1.0, 0.0), 2.0);
+        renderer.line(corner, corner + Vector3::unit_z() * 0.3, FloatColor::from_rgb(0.0, 0.0, 1.0), 2.0);
+        renderer.point(corner, FloatColor::from_rgb(1.0, 1.0, 1.0), 5.0);
 
-        mesh2.transform(Matrix4::from_angle_y(Deg(0.3)));
+        renderer.present();
 
-        {
-            events.pump_events();
-            let keyboard_state = events.keyboard_state();
-            camera.control_with_keyboard(0.04, 0.02, &keyboard_state);
-        }
-
-        renderer.set_from_camera(&camera);
+        sphere_angle += 0.3;
 
         for event in events.poll_iter() {
             match event {
                 Event::Quit{..} => break 'main,
-                _               => continue
+                _               => input.handle_event(&event),
             }
         }
 
+        controller.update(&input, &mut camera);
+        input.end_frame();
+        renderer.set_from_camera(&camera);
+
     }
 }
 
@@ -1,19 +1,62 @@
 use cgmath::*;
+use collision::Ray3;
+#[cfg(feature = "sdl")]
 use sdl2::keyboard::*;
 
+/// How a camera maps view space onto the screen. Off-axis variants take the bounds of the view
+/// volume directly, so the eye needn't be centred on it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// `fov` is the vertical field of view in degrees.
+    Perspective{fov: f32, aspect: f32, near: f32, far: f32},
+    /// A view volume `width` by `height` world units across, centred on the eye.
+    Orthographic{width: f32, height: f32, near: f32, far: f32},
+    /// A perspective frustum whose sides pass through these bounds on the near plane.
+    OffAxisPerspective{left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32},
+    OffAxisOrthographic{left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32},
+}
+
+impl Projection {
+    pub fn matrix(&self) -> Matrix4<f32> {
+        match *self {
+            Projection::Perspective{fov, aspect, near, far} => {
+                Matrix4::from(perspective(Deg(fov), aspect, near, far))
+            },
+            Projection::Orthographic{width, height, near, far} => {
+                ortho(-width / 2.0, width / 2.0, -height / 2.0, height / 2.0, near, far)
+            },
+            Projection::OffAxisPerspective{left, right, bottom, top, near, far} => {
+                frustum(left, right, bottom, top, near, far)
+            },
+            Projection::OffAxisOrthographic{left, right, bottom, top, near, far} => {
+                ortho(left, right, bottom, top, near, far)
+            },
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Camera {
-    fov: f32,
-    aspect: f32,
-    near: f32,
-    far: f32,
+    projection: Projection,
     eye: Matrix4<f32>,
 }
 
 impl Camera {
 
     pub fn create(fov: f32, aspect: f32, near: f32, far: f32, eye: Matrix4<f32>) -> Self {
-        Camera{fov, aspect, near, far, eye}
+        Self::with_projection(Projection::Perspective{fov, aspect, near, far}, eye)
+    }
+
+    pub fn with_projection(projection: Projection, eye: Matrix4<f32>) -> Self {
+        Camera{projection, eye}
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+    }
+
+    pub fn projection_settings(&self) -> Projection {
+        self.projection
     }
 
     pub fn set_eye(&mut self, eye: Matrix4<f32>) {
@@ -24,14 +67,38 @@ impl Camera {
         self.eye
     }
 
+    pub fn position(&self) -> Vector3<f32> {
+        self.eye.w.truncate()
+    }
+
     pub fn projection(&self) -> Matrix4<f32> {
-        Matrix4::from(perspective(Deg(self.fov), self.aspect, self.near, self.far))
+        self.projection.matrix()
+    }
+
+    /// The world-space ray through pixel (`x`, `y`) of a `width` by `height` screen, starting on
+    /// the near plane. Pixel centres are at half-integer coordinates. Returns None if the
+    /// projection can't be inverted, e.g. a zero-size orthographic one or near == far.
+    pub fn unproject(&self, x: f32, y: f32, width: u32, height: u32) -> Option<Ray3<f32>> {
+        let clip_to_world = self.eye * self.projection().invert()?;
+        let ndc_x = x / width as f32 * 2.0 - 1.0;
+        let ndc_y = 1.0 - y / height as f32 * 2.0;
+        let near = clip_to_world * Vector4{x: ndc_x, y: ndc_y, z: -1.0, w: 1.0};
+        let far = clip_to_world * Vector4{x: ndc_x, y: ndc_y, z: 1.0, w: 1.0};
+        let near = near.truncate() / near.w;
+        let far = far.truncate() / far.w;
+        let direction = (far - near).normalize();
+        // A degenerate projection has infinite entries, whose inverse is NaN rather than None.
+        if !(near.magnitude2() + direction.magnitude2()).is_finite() {
+            return None;
+        }
+        Some(Ray3::new(Point3::from_vec(near), direction))
     }
 
     pub fn move_forward(&mut self, distance: f32) {
         self.eye = self.eye * Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -distance});
     }
 
+    #[cfg(feature = "sdl")]
     pub fn control_with_keyboard(
         &mut self,
         move_speed: f32,
@@ -53,3 +120,35 @@ impl Camera {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(projection: Projection, point: Vector3<f32>) -> Vector3<f32> {
+        let clip = projection.matrix() * point.extend(1.0);
+        clip.truncate() / clip.w
+    }
+
+    #[test]
+    fn test_orthographic_projection() {
+        let projection = Projection::Orthographic{width: 4.0, height: 2.0, near: 1.0, far: 11.0};
+        let corner = project(projection, Vector3{x: 2.0, y: -1.0, z: -6.0});
+        assert!((corner - Vector3{x: 1.0, y: -1.0, z: 0.0}).magnitude() < 1e-6);
+        // No perspective: distance doesn't change screen position.
+        let far_corner = project(projection, Vector3{x: 2.0, y: -1.0, z: -11.0});
+        assert!((far_corner.x - 1.0).abs() < 1e-6 && (far_corner.z - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_off_axis_projection() {
+        let projection = Projection::OffAxisPerspective{
+            left: 0.0, right: 2.0, bottom: -1.0, top: 0.5, near: 1.0, far: 10.0,
+        };
+        // The view axis sits on the left edge of the frustum.
+        let on_axis = project(projection, Vector3{x: 0.0, y: 0.0, z: -5.0});
+        assert!((on_axis.x + 1.0).abs() < 1e-6);
+        let top_right = project(projection, Vector3{x: 4.0, y: 1.0, z: -2.0});
+        assert!((top_right.x - 1.0).abs() < 1e-6 && (top_right.y - 1.0).abs() < 1e-6);
+    }
+}
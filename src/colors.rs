@@ -1,7 +1,43 @@
-use sdl2::pixels::Color;
+#[cfg(feature = "sdl")]
+use sdl2;
 use std::ops::*;
 use std::iter::Sum;
 
+/// An 8-bit-per-channel color, as stored in color buffers and textures.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+// Constructor names follow sdl2's Color so existing code keeps reading the same.
+#[allow(non_snake_case)]
+impl Color {
+    pub fn RGB(r: u8, g: u8, b: u8) -> Self {
+        Color{r, g, b, a: 255}
+    }
+
+    pub fn RGBA(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Color{r, g, b, a}
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl From<sdl2::pixels::Color> for Color {
+    fn from(color: sdl2::pixels::Color) -> Self {
+        Color::RGBA(color.r, color.g, color.b, color.a)
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl From<Color> for sdl2::pixels::Color {
+    fn from(color: Color) -> Self {
+        sdl2::pixels::Color::RGBA(color.r, color.g, color.b, color.a)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct FloatColor {
     pub a: f32,
@@ -57,7 +93,7 @@ impl FloatColor {
         )
     }
 
-    pub fn from_sdl_color(color: &Color) -> Self {
+    pub fn from_color(color: &Color) -> Self {
         Self::from_argb_u8s(
             color.a,
             color.r,
@@ -66,7 +102,7 @@ impl FloatColor {
         )
     }
 
-    pub fn as_sdl_color(&self) -> Color {
+    pub fn as_color(&self) -> Color {
         Color::RGBA(
             Self::component_as_u8(self.r),
             Self::component_as_u8(self.g),
@@ -75,6 +111,16 @@ impl FloatColor {
         )
     }
 
+    #[cfg(feature = "sdl")]
+    pub fn from_sdl_color(color: &sdl2::pixels::Color) -> Self {
+        Self::from_color(&Color::from(*color))
+    }
+
+    #[cfg(feature = "sdl")]
+    pub fn as_sdl_color(&self) -> sdl2::pixels::Color {
+        self.as_color().into()
+    }
+
     pub fn mix_colors(colors: &Vec<Self>, weights: &Vec<f32>) -> Self {
         colors.iter().zip(weights).map(|(c, w)| *c * *w).sum()
     }
@@ -120,6 +166,41 @@ impl FloatColor {
     }
 }
 
+/// How a fragment's color is combined with the color already in the buffer. Except for
+/// `Opaque`, the fragment's alpha is its coverage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendMode {
+    /// Replaces the buffer's color.
+    Opaque,
+    /// Standard "over" compositing of an unpremultiplied color.
+    Alpha,
+    /// "Over" compositing of a color already multiplied by its alpha.
+    PremultipliedAlpha,
+    /// Adds the color, weighted by alpha, to the buffer; for glows and particles.
+    Additive,
+    /// Multiplies the buffer by the color, ignoring alpha; for tinting and decals.
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn blend(&self, source: FloatColor, destination: FloatColor) -> FloatColor {
+        let over = |source: FloatColor, weight: f32| FloatColor{
+            a: source.a + destination.a * (1.0 - weight),
+            r: source.r + destination.r * (1.0 - weight),
+            g: source.g + destination.g * (1.0 - weight),
+            b: source.b + destination.b * (1.0 - weight),
+        };
+        let blended = match *self {
+            BlendMode::Opaque => source,
+            BlendMode::Alpha => over(FloatColor{a: source.a, ..source * source.a}, source.a),
+            BlendMode::PremultipliedAlpha => over(source, source.a),
+            BlendMode::Additive => FloatColor{a: destination.a, ..destination + source * source.a},
+            BlendMode::Multiply => FloatColor{a: destination.a, ..FloatColor::multiply_colors(&source, &destination)},
+        };
+        blended.clamped()
+    }
+}
+
 impl Mul<f32> for FloatColor {
     type Output = FloatColor;
 
@@ -151,3 +232,30 @@ impl Sum for FloatColor {
         iter.fold(FloatColor::from_argb(0.0, 0.0, 0.0, 0.0), |a, b| a + b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: FloatColor, b: FloatColor) -> bool {
+        (a.a - b.a).abs() < 1e-6 && (a.r - b.r).abs() < 1e-6 && (a.g - b.g).abs() < 1e-6 && (a.b - b.b).abs() < 1e-6
+    }
+
+    #[test]
+    fn test_blend_modes() {
+        let destination = FloatColor::from_rgb(0.0, 0.5, 1.0);
+        let source = FloatColor::from_argb(0.5, 1.0, 0.0, 0.0);
+        assert!(close(BlendMode::Opaque.blend(source, destination), source));
+        assert!(close(BlendMode::Alpha.blend(source, destination), FloatColor::from_argb(1.0, 0.5, 0.25, 0.5)));
+        let premultiplied = FloatColor::from_argb(0.5, 0.5, 0.0, 0.0);
+        assert!(close(
+            BlendMode::PremultipliedAlpha.blend(premultiplied, destination),
+            BlendMode::Alpha.blend(source, destination),
+        ));
+        assert!(close(BlendMode::Additive.blend(source, destination), FloatColor::from_rgb(0.5, 0.5, 1.0)));
+        assert!(close(
+            BlendMode::Multiply.blend(FloatColor::from_rgb(0.5, 0.5, 0.5), destination),
+            FloatColor::from_rgb(0.0, 0.25, 0.5),
+        ));
+    }
+}
@@ -0,0 +1,197 @@
+use camera::*;
+use cgmath::*;
+use input::*;
+
+pub trait CameraController {
+    /// Advances the controller by one frame of input and moves `camera` to match.
+    fn update(&mut self, input: &InputState, camera: &mut Camera);
+}
+
+/// WASD movement with mouse-look. Q and E move straight down and up, and holding shift moves
+/// faster.
+#[derive(Clone, Copy, Debug)]
+pub struct FirstPersonController {
+    pub position: Vector3<f32>,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+    /// World units moved per frame.
+    pub move_speed: f32,
+    /// Radians turned per pixel of mouse movement.
+    pub look_sensitivity: f32,
+    /// How far the camera can look up or down.
+    pub max_pitch: Rad<f32>,
+}
+
+impl FirstPersonController {
+    pub fn new(position: Vector3<f32>) -> Self {
+        FirstPersonController{
+            position,
+            yaw: Rad(0.0),
+            pitch: Rad(0.0),
+            move_speed: 0.04,
+            look_sensitivity: 0.003,
+            max_pitch: Rad::from(Deg(89.0)),
+        }
+    }
+
+    pub fn eye(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.position)
+            * Matrix4::from_angle_y(self.yaw)
+            * Matrix4::from_angle_x(self.pitch)
+    }
+}
+
+impl CameraController for FirstPersonController {
+    fn update(&mut self, input: &InputState, camera: &mut Camera) {
+        self.yaw = self.yaw - Rad(input.mouse_delta.0 * self.look_sensitivity);
+        let pitch = self.pitch.0 - input.mouse_delta.1 * self.look_sensitivity;
+        self.pitch = Rad(pitch.max(-self.max_pitch.0).min(self.max_pitch.0));
+
+        // Walking ignores pitch, so looking down doesn't slow you down.
+        let forward = Vector3{x: -self.yaw.sin(), y: 0.0, z: -self.yaw.cos()};
+        let right = Vector3{x: self.yaw.cos(), y: 0.0, z: -self.yaw.sin()};
+        let up = Vector3{x: 0.0, y: 1.0, z: 0.0};
+        let mut movement = Vector3::zero();
+        if input.is_key_pressed(Key::W) || input.is_key_pressed(Key::Up) {
+            movement += forward;
+        }
+        if input.is_key_pressed(Key::S) || input.is_key_pressed(Key::Down) {
+            movement -= forward;
+        }
+        if input.is_key_pressed(Key::D) || input.is_key_pressed(Key::Right) {
+            movement += right;
+        }
+        if input.is_key_pressed(Key::A) || input.is_key_pressed(Key::Left) {
+            movement -= right;
+        }
+        if input.is_key_pressed(Key::E) || input.is_key_pressed(Key::Space) {
+            movement += up;
+        }
+        if input.is_key_pressed(Key::Q) {
+            movement -= up;
+        }
+        if movement.magnitude2() > 0.0 {
+            let speed = if input.is_key_pressed(Key::Shift) { self.move_speed * 4.0 } else { self.move_speed };
+            self.position += movement.normalize() * speed;
+        }
+        camera.set_eye(self.eye());
+    }
+}
+
+/// Circles a target point for inspecting models. Dragging with the left button orbits, dragging
+/// with the right or middle button pans, and scrolling zooms.
+#[derive(Clone, Copy, Debug)]
+pub struct OrbitController {
+    pub target: Vector3<f32>,
+    pub distance: f32,
+    pub yaw: Rad<f32>,
+    /// Elevation above the target; positive looks down on it.
+    pub pitch: Rad<f32>,
+    /// Radians turned per pixel dragged.
+    pub rotate_sensitivity: f32,
+    /// Fraction of the distance panned per pixel dragged.
+    pub pan_sensitivity: f32,
+    /// Fraction of the distance zoomed per scroll step.
+    pub zoom_speed: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+    pub max_pitch: Rad<f32>,
+}
+
+impl OrbitController {
+    pub fn new(target: Vector3<f32>, distance: f32) -> Self {
+        OrbitController{
+            target,
+            distance,
+            yaw: Rad(0.0),
+            pitch: Rad(0.0),
+            rotate_sensitivity: 0.005,
+            pan_sensitivity: 0.002,
+            zoom_speed: 0.1,
+            min_distance: 0.01,
+            max_distance: 1000.0,
+            max_pitch: Rad::from(Deg(89.0)),
+        }
+    }
+
+    pub fn eye(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.target)
+            * Matrix4::from_angle_y(self.yaw)
+            * Matrix4::from_angle_x(-self.pitch)
+            * Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: self.distance})
+    }
+}
+
+impl CameraController for OrbitController {
+    fn update(&mut self, input: &InputState, camera: &mut Camera) {
+        let (dx, dy) = input.mouse_delta;
+        if input.is_button_pressed(MouseButton::Left) {
+            self.yaw = self.yaw - Rad(dx * self.rotate_sensitivity);
+            let pitch = self.pitch.0 + dy * self.rotate_sensitivity;
+            self.pitch = Rad(pitch.max(-self.max_pitch.0).min(self.max_pitch.0));
+        } else if input.is_button_pressed(MouseButton::Right) || input.is_button_pressed(MouseButton::Middle) {
+            // Drag the scene along with the mouse, further when zoomed out.
+            let eye = self.eye();
+            let right = eye.x.truncate();
+            let up = eye.y.truncate();
+            self.target += (up * dy - right * dx) * (self.pan_sensitivity * self.distance);
+        }
+        let distance = self.distance * (1.0 - self.zoom_speed).powf(input.scroll);
+        self.distance = distance.max(self.min_distance).min(self.max_distance);
+        camera.set_eye(self.eye());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera::create(70.0, 1.0, 0.1, 100.0, Matrix4::identity())
+    }
+
+    fn forward(camera: &Camera) -> Vector3<f32> {
+        -camera.eye().z.truncate()
+    }
+
+    #[test]
+    fn test_first_person_controller() {
+        let mut camera = camera();
+        let mut controller = FirstPersonController::new(Vector3::zero());
+        let mut input = InputState::new();
+        // Turn a quarter to the right, then walk forwards.
+        input.move_mouse(Rad::from(Deg(90.0)).0 / controller.look_sensitivity, 0.0);
+        input.set_key(Key::W, true);
+        controller.update(&input, &mut camera);
+        assert!((forward(&camera) - Vector3{x: 1.0, y: 0.0, z: 0.0}).magnitude() < 1e-4);
+        assert!((camera.position() - Vector3{x: controller.move_speed, y: 0.0, z: 0.0}).magnitude() < 1e-4);
+
+        input.end_frame();
+        input.move_mouse(0.0, -100000.0);
+        controller.update(&input, &mut camera);
+        assert_eq!(controller.pitch, controller.max_pitch);
+        assert!(forward(&camera).y > 0.99);
+    }
+
+    #[test]
+    fn test_orbit_controller() {
+        let mut camera = camera();
+        let target = Vector3{x: 1.0, y: 2.0, z: 3.0};
+        let mut controller = OrbitController::new(target, 5.0);
+        let mut input = InputState::new();
+        input.set_button(MouseButton::Left, true);
+        input.move_mouse(123.0, 45.0);
+        controller.update(&input, &mut camera);
+        // Orbiting keeps the camera facing the target at the same distance.
+        let offset = target - camera.position();
+        assert!((offset.magnitude() - 5.0).abs() < 1e-4);
+        assert!((offset.normalize() - forward(&camera)).magnitude() < 1e-4);
+        assert!(camera.position().y > target.y);
+
+        input.end_frame();
+        input.set_button(MouseButton::Left, false);
+        input.scroll(-1000.0);
+        controller.update(&input, &mut camera);
+        assert_eq!(controller.distance, controller.max_distance);
+    }
+}
@@ -0,0 +1,554 @@
+// A small DEFLATE (RFC 1951) implementation wrapped in the zlib format (RFC 1950), which is all
+// PNG needs. Compression uses LZ77 with hash chains and the fixed Huffman code, which is a long
+// way from optimal but simple and plenty for frame dumps. Decompression handles every block type,
+// since files written by other tools almost always use dynamic Huffman codes.
+
+use std;
+
+pub const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+
+pub const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+pub const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+pub const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const MAX_CODE_LENGTH: usize = 15;
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 15;
+const TOO_LONG: &str = "deflate output is longer than the limit";
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continues a CRC computed over earlier data, so that PNG chunk types and contents can be
+/// checksummed without concatenating them.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Compresses `data` into a zlib stream.
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    // 32K window, deflate, default compression level; the check bits make the header a
+    // multiple of 31.
+    let mut output = vec![0x78, 0x9C];
+    output.append(&mut deflate(data));
+    let checksum = adler32(data);
+    output.extend_from_slice(&[
+        (checksum >> 24) as u8,
+        (checksum >> 16) as u8,
+        (checksum >> 8) as u8,
+        checksum as u8,
+    ]);
+    output
+}
+
+/// Compresses `data` into a raw DEFLATE stream consisting of a single fixed-Huffman block.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman codes)
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    let mut head = vec![usize::max_value(); 1 << HASH_BITS];
+    let mut prev = vec![usize::max_value(); WINDOW_SIZE];
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = longest_match(data, i, &head, &prev);
+        if length >= MIN_MATCH {
+            write_length(&mut writer, length);
+            write_distance(&mut writer, distance);
+            for j in i..i + length {
+                insert_hash(data, j, &mut head, &mut prev);
+            }
+            i += length;
+        } else {
+            write_literal_length_code(&mut writer, data[i] as u16);
+            insert_hash(data, i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    // end of block
+    write_literal_length_code(&mut writer, 256);
+    writer.finish()
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let value = ((data[i] as usize) << 10) ^ ((data[i + 1] as usize) << 5) ^ (data[i + 2] as usize);
+    value & ((1 << HASH_BITS) - 1)
+}
+
+fn insert_hash(data: &[u8], i: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>) {
+    if i + MIN_MATCH > data.len() {
+        return;
+    }
+    let h = hash(data, i);
+    prev[i % WINDOW_SIZE] = head[h];
+    head[h] = i;
+}
+
+fn longest_match(data: &[u8], i: usize, head: &Vec<usize>, prev: &Vec<usize>) -> (usize, usize) {
+    if i + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let max_length = std::cmp::min(MAX_MATCH, data.len() - i);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(data, i)];
+    let mut chain = 0;
+    while candidate != usize::max_value() && chain < MAX_CHAIN {
+        if candidate >= i || i - candidate > WINDOW_SIZE {
+            break;
+        }
+        let mut length = 0;
+        while length < max_length && data[candidate + length] == data[i + length] {
+            length += 1;
+        }
+        if length > best.0 {
+            best = (length, i - candidate);
+            if length == max_length {
+                break;
+            }
+        }
+        let next = prev[candidate % WINDOW_SIZE];
+        if next >= candidate {
+            break;
+        }
+        candidate = next;
+        chain += 1;
+    }
+    best
+}
+
+fn write_literal_length_code(writer: &mut BitWriter, symbol: u16) {
+    let (code, length) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    };
+    writer.write_huffman_code(code as u32, length);
+}
+
+fn write_length(writer: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASE.iter().rposition(|base| *base as usize <= length).unwrap();
+    write_literal_length_code(writer, 257 + index as u16);
+    writer.write_bits((length - LENGTH_BASE[index] as usize) as u32, LENGTH_EXTRA_BITS[index] as u32);
+}
+
+fn write_distance(writer: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASE.iter().rposition(|base| *base as usize <= distance).unwrap();
+    writer.write_huffman_code(index as u32, 5);
+    writer.write_bits(
+        (distance - DISTANCE_BASE[index] as usize) as u32,
+        DISTANCE_EXTRA_BITS[index] as u32,
+    );
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter{bytes: vec![], buffer: 0, count: 0}
+    }
+
+    // Data elements are packed least significant bit first.
+    fn write_bits(&mut self, value: u32, count: u32) {
+        for bit in 0..count {
+            self.buffer |= ((value >> bit) & 1) << self.count;
+            self.count += 1;
+            if self.count == 8 {
+                self.bytes.push(self.buffer as u8);
+                self.buffer = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    // Huffman codes are packed most significant bit first.
+    fn write_huffman_code(&mut self, code: u32, length: u32) {
+        for bit in (0..length).rev() {
+            self.write_bits((code >> bit) & 1, 1);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Decompresses a zlib stream, checking its header and checksum.
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    zlib_decompress_limited(data, usize::MAX)
+}
+
+/// Like `zlib_decompress`, but fails rather than produce more than `limit` bytes, so a small
+/// stream can't expand to fill memory.
+pub fn zlib_decompress_limited(data: &[u8], limit: usize) -> Result<Vec<u8>, &'static str> {
+    if data.len() < 6 {
+        return Err("zlib stream is truncated");
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0F != 8 || cmf >> 4 > 7 {
+        return Err("zlib stream does not use deflate");
+    }
+    if ((cmf as u16) << 8 | flg as u16) % 31 != 0 {
+        return Err("zlib header check failed");
+    }
+    if flg & 0x20 != 0 {
+        return Err("zlib preset dictionaries are not supported");
+    }
+    let (output, consumed) = inflate_limited(&data[2..], limit)?;
+    let trailer = &data[2 + consumed..];
+    if trailer.len() < 4 {
+        return Err("zlib stream is missing its checksum");
+    }
+    let checksum = (trailer[0] as u32) << 24 | (trailer[1] as u32) << 16
+        | (trailer[2] as u32) << 8 | trailer[3] as u32;
+    if checksum != adler32(&output) {
+        return Err("zlib checksum mismatch");
+    }
+    Ok(output)
+}
+
+/// Decompresses a raw DEFLATE stream, returning the data and the number of input bytes used.
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize), &'static str> {
+    inflate_limited(data, usize::MAX)
+}
+
+/// Like `inflate`, but fails rather than produce more than `limit` bytes.
+pub fn inflate_limited(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize), &'static str> {
+    let mut reader = BitReader::new(data);
+    let mut output = vec![];
+    loop {
+        let last = reader.read_bits(1)? == 1;
+        match reader.read_bits(2)? {
+            0 => inflate_stored(&mut reader, &mut output, limit)?,
+            1 => {
+                let (literal_lengths, distances) = fixed_codes();
+                inflate_codes(&mut reader, &mut output, &literal_lengths, &distances, limit)?;
+            },
+            2 => {
+                let (literal_lengths, distances) = dynamic_codes(&mut reader)?;
+                inflate_codes(&mut reader, &mut output, &literal_lengths, &distances, limit)?;
+            },
+            _ => return Err("invalid deflate block type"),
+        }
+        if last {
+            break;
+        }
+    }
+    Ok((output, reader.bytes_consumed()))
+}
+
+fn inflate_stored(reader: &mut BitReader, output: &mut Vec<u8>, limit: usize) -> Result<(), &'static str> {
+    reader.align_to_byte();
+    let length = reader.read_bits(16)?;
+    let complement = reader.read_bits(16)?;
+    if length != !complement & 0xFFFF {
+        return Err("stored block length check failed");
+    }
+    if output.len() + length as usize > limit {
+        return Err(TOO_LONG);
+    }
+    for _ in 0..length {
+        output.push(reader.read_bits(8)? as u8);
+    }
+    Ok(())
+}
+
+fn inflate_codes(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    literal_lengths: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> Result<(), &'static str> {
+    loop {
+        let symbol = literal_lengths.decode(reader)? as usize;
+        if symbol < 256 {
+            if output.len() >= limit {
+                return Err(TOO_LONG);
+            }
+            output.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let index = symbol - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err("invalid length code");
+            }
+            let length = LENGTH_BASE[index] as usize
+                + reader.read_bits(LENGTH_EXTRA_BITS[index] as u32)? as usize;
+            let distance_symbol = distances.decode(reader)? as usize;
+            if distance_symbol >= DISTANCE_BASE.len() {
+                return Err("invalid distance code");
+            }
+            let distance = DISTANCE_BASE[distance_symbol] as usize
+                + reader.read_bits(DISTANCE_EXTRA_BITS[distance_symbol] as u32)? as usize;
+            if distance > output.len() {
+                return Err("distance reaches before start of output");
+            }
+            if output.len() + length > limit {
+                return Err(TOO_LONG);
+            }
+            let start = output.len() - distance;
+            for i in 0..length {
+                let byte = output[start + i];
+                output.push(byte);
+            }
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), &'static str> {
+    let literal_length_count = reader.read_bits(5)? as usize + 257;
+    let distance_count = reader.read_bits(5)? as usize + 1;
+    let code_length_count = reader.read_bits(4)? as usize + 4;
+
+    let mut code_length_lengths = [0; 19];
+    for i in 0..code_length_count {
+        code_length_lengths[CODE_LENGTH_ORDER[i]] = reader.read_bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_length_lengths)?;
+
+    let mut lengths = vec![];
+    while lengths.len() < literal_length_count + distance_count {
+        let symbol = code_lengths.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match lengths.last() {
+                Some(previous) => (*previous, 3 + reader.read_bits(2)?),
+                None => return Err("repeated code length with no previous length"),
+            },
+            17 => (0, 3 + reader.read_bits(3)?),
+            _ => (0, 11 + reader.read_bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() > literal_length_count + distance_count {
+        return Err("code lengths overrun their table");
+    }
+    if lengths[256] == 0 {
+        return Err("no code for end of block");
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_length_count])?,
+        Huffman::new(&lengths[literal_length_count..])?,
+    ))
+}
+
+// A canonical Huffman code, decoded a bit at a time: for each code length we know how many
+// codes there are and which symbols they belong to, in order.
+struct Huffman {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, &'static str> {
+        let mut counts = [0; MAX_CODE_LENGTH + 1];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        // Reject codes that use more bit patterns than exist.
+        let mut left: i32 = 1;
+        for length in 1..MAX_CODE_LENGTH + 1 {
+            left = (left << 1) - counts[length] as i32;
+            if left < 0 {
+                return Err("over-subscribed huffman code");
+            }
+        }
+        let mut offsets = [0; MAX_CODE_LENGTH + 2];
+        for length in 1..MAX_CODE_LENGTH + 1 {
+            offsets[length + 1] = offsets[length] + counts[length] as usize;
+        }
+        let mut symbols = vec![0; offsets[MAX_CODE_LENGTH + 1]];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize]] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        Ok(Huffman{counts, symbols})
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, &'static str> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..MAX_CODE_LENGTH + 1 {
+            code |= reader.read_bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid huffman code")
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl <'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader{data, position: 0, buffer: 0, count: 0}
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, &'static str> {
+        while self.count < count {
+            if self.position >= self.data.len() {
+                return Err("deflate stream is truncated");
+            }
+            self.buffer |= (self.data[self.position] as u32) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << count) - 1) as u32;
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn bytes_consumed(&self) -> usize {
+        self.position - (self.count / 8) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = b"the quick brown fox jumps over the lazy dog. ".iter()
+            .cycle()
+            .take(5000)
+            .cloned()
+            .chain((0..5000).map(|i| (i * i % 251) as u8))
+            .collect();
+        let compressed = zlib_compress(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(zlib_decompress(&compressed), Ok(data));
+    }
+
+    #[test]
+    fn test_inflate_dynamic_and_stored_blocks() {
+        // A dynamic Huffman block, from Python's zlib.compress(data, 9).
+        let data = b"abbaadbabbabadcaabaababcbaabcaabacdbababcaacbaacaccaabbddabcdaabcbadadaaaaaaabac";
+        let dynamic = [
+            0x78, 0xDA, 0x25, 0x8A, 0x81, 0x0D, 0x00, 0x30, 0x08, 0xC2, 0x6E, 0x2D, 0xF0, 0xFF,
+            0x0D, 0x03, 0xA7, 0x06, 0xB4, 0x82, 0x04, 0x11, 0x35, 0x11, 0x53, 0xED, 0xC8, 0xB3,
+            0xBB, 0xBC, 0xE7, 0xED, 0x63, 0xC6, 0xA3, 0x4A, 0x8A, 0xC2, 0x05, 0xD3, 0xFE, 0xD5,
+            0xF4, 0x03, 0xD6, 0xFE, 0x1E, 0x97,
+        ];
+        assert_eq!(zlib_decompress(&dynamic), Ok(data.to_vec()));
+        // zlib.compress(b"hello", 0), a single stored block.
+        let stored = [
+            0x78, 0x01, 0x01, 0x05, 0x00, 0xFA, 0xFF, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x06, 0x2C,
+            0x02, 0x15,
+        ];
+        assert_eq!(zlib_decompress(&stored), Ok(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_decompression_limit() {
+        // A megabyte of zeros compresses to about a kilobyte.
+        let compressed = zlib_compress(&vec![0; 1 << 20]);
+        assert_eq!(zlib_decompress_limited(&compressed, 1 << 20).map(|data| data.len()), Ok(1 << 20));
+        assert_eq!(zlib_decompress_limited(&compressed, 1000), Err(TOO_LONG));
+    }
+
+    #[test]
+    fn test_deflate_empty() {
+        // A fixed block holding only the end-of-block code.
+        assert_eq!(deflate(&[]), vec![0x03, 0x00]);
+    }
+}
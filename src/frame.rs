@@ -1,3 +1,4 @@
+#[derive(Clone, Debug)]
 pub struct Frame<T> {
     pub width: usize,
     pub height: usize,
@@ -1,15 +1,22 @@
 use cgmath::*;
+use collision::{Aabb3, Sphere};
 use math::*;
+use shaders::Varying;
+use std::collections::HashMap;
 use std::f32::consts::PI;
 
+/// A vertex of a mesh. Besides position, uv and normal, a vertex carries an arbitrary payload of
+/// extra attributes (colors, tangents, a second uv set...) which is clipped and interpolated
+/// along with everything else. Attributes are passed through transformations untouched.
 #[derive(Clone, Copy, Debug)]
-pub struct Vertex3 {
+pub struct Vertex3<A = ()> {
     pub position: Vector3<f32>,
     pub uv: Vector2<f32>,
     pub normal: Vector3<f32>,
+    pub attributes: A,
 }
 
-impl Vertex3 {
+impl <A: Copy> Vertex3<A> {
     pub fn transform_with_correction(&mut self, transformation: Matrix4<f32>) {
         let homogenous_coordinates = transformation * self.position.extend(1.0);
         let normal_matrix = transformation.invert().unwrap().transpose();
@@ -27,6 +34,7 @@ impl Vertex3 {
             position: (transformation * self.position.extend(1.0)).truncate(),
             uv: self.uv,
             normal: (normal_matrix * self.normal.extend(1.0)).truncate(),
+            attributes: self.attributes,
         };
     }
 
@@ -36,30 +44,62 @@ impl Vertex3 {
         return transformed;
     }
 
-    pub fn to_vertex4(&self, w: f32) -> Vertex4 {
+    pub fn to_vertex4(&self, w: f32) -> Vertex4<A> {
         return Vertex4{
             position: self.position.extend(w),
             uv: self.uv,
             normal: self.normal.extend(1.0),
+            attributes: self.attributes,
+        }
+    }
+
+    pub fn with_attributes<B>(&self, attributes: B) -> Vertex3<B> {
+        return Vertex3{
+            position: self.position,
+            uv: self.uv,
+            normal: self.normal,
+            attributes,
+        };
+    }
+}
+
+impl <A: Varying> Varying for Vertex3<A> {
+    fn scaled(&self, factor: f32) -> Self {
+        Vertex3{
+            position: self.position * factor,
+            uv: self.uv * factor,
+            normal: self.normal * factor,
+            attributes: self.attributes.scaled(factor),
+        }
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        Vertex3{
+            position: self.position + other.position,
+            uv: self.uv + other.uv,
+            normal: self.normal + other.normal,
+            attributes: self.attributes.added(&other.attributes),
         }
     }
 }
 
 #[derive(Clone, Copy, Debug)]
-pub struct Vertex4 {
+pub struct Vertex4<A = ()> {
     pub position: Vector4<f32>,
     pub uv: Vector2<f32>,
     // could be Vector3 but makes clipping easier this way
     pub normal: Vector4<f32>,
+    pub attributes: A,
 }
 
-impl Vertex4 {
+impl <A: Copy> Vertex4<A> {
     pub fn transformed(&self, transformation: Matrix4<f32>) -> Self {
         let normal_matrix = transformation.invert().unwrap().transpose();
         return Vertex4 {
             position: transformation * self.position,
             uv: self.uv,
             normal: normal_matrix * self.normal,
+            attributes: self.attributes,
         };
     }
 
@@ -72,25 +112,45 @@ impl Vertex4 {
     }
 }
 
-/*
-pub fn clip_triangle(triangle: &(&Vertex4, &Vertex4, &Vertex4)) -> Vec<(Vertex4, Vertex4, Vertex4)> {
-    let mut new_triangle = triangle.clone();
-    let points = vec![
-        triangle.0.position,
-        triangle.1.position,
-        triangle.2.position,
-    ];
-    let clipped_points = clip_in_box(&points);
+impl <A: Varying> Varying for Vertex4<A> {
+    fn scaled(&self, factor: f32) -> Self {
+        Vertex4{
+            position: self.position * factor,
+            uv: self.uv * factor,
+            normal: self.normal * factor,
+            attributes: self.attributes.scaled(factor),
+        }
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        Vertex4{
+            position: self.position + other.position,
+            uv: self.uv + other.uv,
+            normal: self.normal + other.normal,
+            attributes: self.attributes.added(&other.attributes),
+        }
+    }
+}
+
+// A Vertex4 whose position is in clip space can be clipped directly, which interpolates its
+// uv, normal and attributes at the cut.
+impl <A: Varying> Homogenous<f32> for Vertex4<A> {
+    fn homogenous_position(&self) -> Vector4<f32> {
+        self.position
+    }
+
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Varying::lerp(self, other, t)
+    }
 }
-*/
 
 #[derive(Clone, Debug)]
-pub struct Mesh {
-    pub vertices: Vec<(Vertex3, Vertex3, Vertex3)>,
+pub struct Mesh<A = ()> {
+    pub vertices: Vec<(Vertex3<A>, Vertex3<A>, Vertex3<A>)>,
 }
 
-impl Mesh {
-    pub fn from_triangles(vertices: &Vec<Vertex3>, triangles: &Vec<(usize, usize, usize)>) -> Self {
+impl <A: Copy> Mesh<A> {
+    pub fn from_triangles(vertices: &Vec<Vertex3<A>>, triangles: &Vec<(usize, usize, usize)>) -> Self {
         let vertices = triangles.iter().map(|tri| {
             (vertices[tri.0], vertices[tri.1], vertices[tri.2])
         }).collect();
@@ -99,6 +159,68 @@ impl Mesh {
         };
     }
 
+    /// Builds a mesh with the same geometry, computing each vertex's attributes from the
+    /// original vertex.
+    pub fn map_attributes<B, F>(&self, f: F) -> Mesh<B> where F: Fn(&Vertex3<A>) -> B {
+        let vertices = self.vertices.iter().map(|(v0, v1, v2)| {
+            (v0.with_attributes(f(v0)), v1.with_attributes(f(v1)), v2.with_attributes(f(v2)))
+        }).collect();
+        return Mesh{
+            vertices,
+        };
+    }
+
+    pub fn transform(&mut self, transformation: Matrix4<f32>) {
+        for triangle in &mut self.vertices {
+            triangle.0.transform_with_correction(transformation);
+            triangle.1.transform_with_correction(transformation);
+            triangle.2.transform_with_correction(transformation);
+        }
+    }
+
+    pub fn transformed(&self, transformation: Matrix4<f32>) -> Self {
+        let mut mesh = self.clone();
+        for (v0, v1, v2) in &mut mesh.vertices {
+            v0.transform_with_correction(transformation);
+            v1.transform_with_correction(transformation);
+            v2.transform_with_correction(transformation);
+        }
+        return mesh;
+    }
+
+    /// The smallest axis-aligned box containing the mesh, or None if it has no triangles.
+    pub fn bounding_box(&self) -> Option<Aabb3<f32>> {
+        bounding_box(self.positions())
+    }
+
+    /// A sphere containing the mesh, centred on its bounding box.
+    pub fn bounding_sphere(&self) -> Option<Sphere<f32>> {
+        self.bounds().map(|bounds| bounds.sphere)
+    }
+
+    /// Both bounding volumes at once, for keeping alongside the mesh.
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.bounding_box().map(|aabb| Bounds{aabb, sphere: bounding_sphere(aabb, self.positions())})
+    }
+
+    fn positions<'a>(&'a self) -> impl Iterator<Item = Vector3<f32>> + 'a {
+        self.vertices.iter().flat_map(|&(v0, v1, v2)| {
+            std::iter::once(v0.position).chain(std::iter::once(v1.position)).chain(std::iter::once(v2.position))
+        })
+    }
+
+    pub fn compute_normals(&mut self) {
+        for (v0, v1, v2) in &mut self.vertices {
+            let normal = (v1.position - v0.position).cross(v2.position - v0.position);
+            let normalized_normal = normal / normal.magnitude();
+            v0.normal = normalized_normal;
+            v1.normal = normalized_normal;
+            v2.normal = normalized_normal;
+        }
+    }
+}
+
+impl Mesh {
     pub fn xy_face(size: f32) -> Self {
         return Self::from_triangles(
             &vec![
@@ -106,21 +228,25 @@ impl Mesh {
                     position: Vector3{x: -size / 2.0, y: -size / 2.0, z: 0.0},
                     uv: Vector2{x: 0.0, y: 0.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: size / 2.0, y: -size / 2.0, z: 0.0},
                     uv: Vector2{x: 1.0, y: 0.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: -size / 2.0, y: size / 2.0, z: 0.0},
                     uv: Vector2{x: 0.0, y: 1.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: size / 2.0, y: size / 2.0, z: 0.0},
                     uv: Vector2{x: 1.0, y: 1.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
             ],
             &vec![
@@ -137,41 +263,49 @@ impl Mesh {
                     position: Vector3{x: -size / 2.0, y: -size / 2.0, z: size / 2.0},
                     uv: Vector2{x: 0.0, y: 0.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: size / 2.0, y: -size / 2.0, z: size / 2.0},
                     uv: Vector2{x: 1.0, y: 0.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: -size / 2.0, y: size / 2.0, z: size / 2.0},
                     uv: Vector2{x: 0.0, y: 1.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: size / 2.0, y: size / 2.0, z: size / 2.0},
                     uv: Vector2{x: 1.0, y: 1.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: -size / 2.0, y: -size / 2.0, z: -size / 2.0},
                     uv: Vector2{x: 1.0, y: 0.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: size / 2.0, y: -size / 2.0, z: -size / 2.0},
                     uv: Vector2{x: 0.0, y: 0.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: -size / 2.0, y: size / 2.0, z: -size / 2.0},
                     uv: Vector2{x: 1.0, y: 1.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
                 Vertex3 {
                     position: Vector3{x: size / 2.0, y: size / 2.0, z: -size / 2.0},
                     uv: Vector2{x: 0.0, y: 1.0},
                     normal: Vector3{x: 0.0, y: 0.0, z: 1.0},
+                    attributes: (),
                 },
             ],
             &vec![
@@ -223,16 +357,19 @@ impl Mesh {
                     position: *v0,
                     uv: Vector2{x: 0.0, y: 0.0},
                     normal: *v0 / v0.magnitude(),
+                    attributes: (),
                 },
                 Vertex3{
                     position: *v1,
                     uv: Vector2{x: 0.0, y: 0.0},
                     normal: *v1 / v1.magnitude(),
+                    attributes: (),
                 },
                 Vertex3{
                     position: *v2,
                     uv: Vector2{x: 0.0, y: 0.0},
                     normal: *v2 / v2.magnitude(),
+                    attributes: (),
                 },
             ))
             .collect();
@@ -262,32 +399,158 @@ impl Mesh {
             z: size * polar_angle.sin() * azimuthal_angle.sin(),
         }
     }
+}
+
+/// A mesh stored as a vertex buffer plus triangles indexing into it, so that vertices shared
+/// between triangles are stored, and transformed by the renderer, only once.
+#[derive(Clone, Debug)]
+pub struct IndexedMesh<A = ()> {
+    pub vertices: Vec<Vertex3<A>>,
+    pub indices: Vec<(usize, usize, usize)>,
+}
+
+impl <A: Copy> IndexedMesh<A> {
+    pub fn new(vertices: Vec<Vertex3<A>>, indices: Vec<(usize, usize, usize)>) -> Self {
+        return IndexedMesh{
+            vertices,
+            indices,
+        };
+    }
+
+    /// Builds an indexed mesh from a triangle soup, merging vertices that are exactly equal.
+    pub fn from_mesh(mesh: &Mesh<A>) -> Self where A: PartialEq {
+        let mut vertices: Vec<Vertex3<A>> = vec![];
+        // Vertices bucketed by the bits of their position, uv and normal; attributes are only
+        // comparable, so they're checked within a bucket.
+        let mut buckets: HashMap<[u32; 8], Vec<usize>> = HashMap::new();
+        let mut index_of = |vertex: &Vertex3<A>| {
+            let key = [
+                vertex.position.x.to_bits(),
+                vertex.position.y.to_bits(),
+                vertex.position.z.to_bits(),
+                vertex.uv.x.to_bits(),
+                vertex.uv.y.to_bits(),
+                vertex.normal.x.to_bits(),
+                vertex.normal.y.to_bits(),
+                vertex.normal.z.to_bits(),
+            ];
+            let bucket = buckets.entry(key).or_insert(vec![]);
+            match bucket.iter().find(|&&index| vertices[index].attributes == vertex.attributes) {
+                Some(&index) => index,
+                None => {
+                    vertices.push(*vertex);
+                    bucket.push(vertices.len() - 1);
+                    vertices.len() - 1
+                },
+            }
+        };
+        let indices = mesh.vertices.iter()
+            .map(|(v0, v1, v2)| (index_of(v0), index_of(v1), index_of(v2)))
+            .collect();
+        return IndexedMesh{
+            vertices,
+            indices,
+        };
+    }
+
+    pub fn to_mesh(&self) -> Mesh<A> {
+        return Mesh::from_triangles(&self.vertices, &self.indices);
+    }
 
     pub fn transform(&mut self, transformation: Matrix4<f32>) {
-        for triangle in &mut self.vertices {
-            triangle.0.transform_with_correction(transformation);
-            triangle.1.transform_with_correction(transformation);
-            triangle.2.transform_with_correction(transformation);
+        for vertex in &mut self.vertices {
+            vertex.transform_with_correction(transformation);
         }
     }
 
     pub fn transformed(&self, transformation: Matrix4<f32>) -> Self {
         let mut mesh = self.clone();
-        for (v0, v1, v2) in &mut mesh.vertices {
-            v0.transform_with_correction(transformation);
-            v1.transform_with_correction(transformation);
-            v2.transform_with_correction(transformation);
-        }
+        mesh.transform(transformation);
         return mesh;
     }
 
-    pub fn compute_normals(&mut self) {
-        for (v0, v1, v2) in &mut self.vertices {
-            let normal = (v1.position - v0.position).cross(v2.position - v0.position);
-            let normalized_normal = normal / normal.magnitude();
-            v0.normal = normalized_normal;
-            v1.normal = normalized_normal;
-            v2.normal = normalized_normal;
+    pub fn bounding_box(&self) -> Option<Aabb3<f32>> {
+        bounding_box(self.vertices.iter().map(|vertex| vertex.position))
+    }
+
+    pub fn bounding_sphere(&self) -> Option<Sphere<f32>> {
+        self.bounds().map(|bounds| bounds.sphere)
+    }
+
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.bounding_box().map(|aabb| Bounds{
+            aabb,
+            sphere: bounding_sphere(aabb, self.vertices.iter().map(|vertex| vertex.position)),
+        })
+    }
+}
+
+/// A mesh's bounding box and sphere in object space. Finding them walks the whole mesh, so for
+/// meshes drawn every frame it's worth computing them once and handing them to
+/// `Renderer::mesh_with_bounds`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub aabb: Aabb3<f32>,
+    pub sphere: Sphere<f32>,
+}
+
+fn bounding_box<I: Iterator<Item = Vector3<f32>>>(mut positions: I) -> Option<Aabb3<f32>> {
+    let first = Point3::from_vec(positions.next()?);
+    let mut bounds = Aabb3::new(first, first);
+    for position in positions {
+        bounds = Aabb3::new(
+            Point3::new(bounds.min.x.min(position.x), bounds.min.y.min(position.y), bounds.min.z.min(position.z)),
+            Point3::new(bounds.max.x.max(position.x), bounds.max.y.max(position.y), bounds.max.z.max(position.z)),
+        );
+    }
+    Some(bounds)
+}
+
+// Tighter than the box's circumscribed sphere unless the mesh reaches into the box's corners.
+fn bounding_sphere<I: Iterator<Item = Vector3<f32>>>(bounds: Aabb3<f32>, positions: I) -> Sphere<f32> {
+    let center = bounds.min.midpoint(bounds.max);
+    let radius = positions
+        .map(|position| (position - center.to_vec()).magnitude())
+        .fold(0.0, f32::max);
+    Sphere{center, radius}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounding_volumes() {
+        let cube = Mesh::cube(2.0).transformed(Matrix4::from_translation(Vector3{x: 1.0, y: 0.0, z: 0.0}));
+        let bounds = cube.bounding_box().unwrap();
+        assert_eq!(bounds.min, Point3::new(0.0, -1.0, -1.0));
+        assert_eq!(bounds.max, Point3::new(2.0, 1.0, 1.0));
+        let sphere = IndexedMesh::from_mesh(&cube).bounding_sphere().unwrap();
+        assert_eq!(sphere.center, Point3::new(1.0, 0.0, 0.0));
+        assert!((sphere.radius - 3.0f32.sqrt()).abs() < 1e-5);
+        assert!(Mesh::<()>{vertices: vec![]}.bounding_sphere().is_none());
+        assert_eq!(cube.bounds(), Some(Bounds{aabb: bounds, sphere: cube.bounding_sphere().unwrap()}));
+    }
+
+    #[test]
+    fn test_indexed_mesh_round_trip() {
+        let face = Mesh::xy_face(2.0);
+        let indexed = IndexedMesh::from_mesh(&face);
+        assert_eq!(indexed.vertices.len(), 4);
+        assert_eq!(indexed.indices, vec![(0, 1, 2), (1, 3, 2)]);
+        let mesh = indexed.to_mesh();
+        assert_eq!(mesh.vertices.len(), 2);
+        for (a, b) in mesh.vertices.iter().zip(face.vertices.iter()) {
+            assert_eq!((a.0.position, a.1.position, a.2.position), (b.0.position, b.1.position, b.2.position));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_indexed_mesh_keeps_distinct_attributes() {
+        let face = Mesh::xy_face(2.0).map_attributes(|vertex| vertex.position.x);
+        let mut tinted = face.clone();
+        tinted.vertices[1].0.attributes = 5.0;
+        assert_eq!(IndexedMesh::from_mesh(&face).vertices.len(), 4);
+        assert_eq!(IndexedMesh::from_mesh(&tinted).vertices.len(), 5);
+    }
+}
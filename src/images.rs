@@ -0,0 +1,1095 @@
+use colors::Color;
+use deflate::*;
+use frame::Frame;
+use std;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Pass origins and spacing for Adam7 interlacing: (x start, y start, x step, y step).
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    /// The file is not a valid image of the format it claims to be.
+    Malformed(String),
+    /// The file is valid, but uses a feature (or format) that isn't supported.
+    Unsupported(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Io(ref error) => write!(f, "i/o error: {}", error),
+            ImageError::Malformed(ref message) => write!(f, "malformed image: {}", message),
+            ImageError::Unsupported(ref message) => write!(f, "unsupported image: {}", message),
+        }
+    }
+}
+
+impl Error for ImageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ImageError::Io(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(error: io::Error) -> Self {
+        ImageError::Io(error)
+    }
+}
+
+fn malformed<T>(message: &str) -> Result<T, ImageError> {
+    Err(ImageError::Malformed(message.to_string()))
+}
+
+fn unsupported<T>(message: &str) -> Result<T, ImageError> {
+    Err(ImageError::Unsupported(message.to_string()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Bmp,
+    Tga,
+    Ppm,
+}
+
+impl ImageFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_ref().map(|extension| extension.as_str()) {
+            Some("png") => Some(ImageFormat::Png),
+            Some("bmp") => Some(ImageFormat::Bmp),
+            Some("tga") => Some(ImageFormat::Tga),
+            Some("ppm") | Some("pgm") | Some("pnm") => Some(ImageFormat::Ppm),
+            _ => None,
+        }
+    }
+
+    /// Recognizes a format from the first bytes of a file. TGA has no signature, so it is
+    /// never detected this way.
+    pub fn from_signature(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&PNG_SIGNATURE) {
+            Some(ImageFormat::Png)
+        } else if bytes.starts_with(b"BM") {
+            Some(ImageFormat::Bmp)
+        } else if bytes.len() >= 2 && bytes[0] == b'P' && b"2356".contains(&bytes[1]) {
+            Some(ImageFormat::Ppm)
+        } else {
+            None
+        }
+    }
+}
+
+impl Frame<Color> {
+    /// Loads an image file, recognizing the format from its contents or else its extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ImageError> {
+        let mut bytes = vec![];
+        File::open(&path)?.read_to_end(&mut bytes)?;
+        match ImageFormat::from_signature(&bytes).or(ImageFormat::from_path(&path)) {
+            Some(format) => Self::decode(&bytes, format),
+            None => unsupported("unrecognized image format"),
+        }
+    }
+
+    pub fn read<R: Read>(reader: &mut R, format: ImageFormat) -> Result<Self, ImageError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::decode(&bytes, format)
+    }
+
+    pub fn decode(bytes: &[u8], format: ImageFormat) -> Result<Self, ImageError> {
+        match format {
+            ImageFormat::Png => decode_png(bytes),
+            ImageFormat::Bmp => decode_bmp(bytes),
+            ImageFormat::Tga => decode_tga(bytes),
+            ImageFormat::Ppm => decode_ppm(bytes),
+        }
+    }
+
+    /// Saves the frame, choosing the format from the file extension (`ppm`, `bmp` or `png`).
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let extension = path.as_ref().extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase())
+            .unwrap_or_default();
+        let write: fn(&Self, &mut BufWriter<File>) -> io::Result<()> = match extension.as_str() {
+            "ppm" => Self::write_ppm,
+            "bmp" => Self::write_bmp,
+            "png" => Self::write_png,
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported image extension, expected ppm, bmp or png",
+            )),
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        write(self, &mut writer)?;
+        writer.flush()
+    }
+
+    /// Writes a binary (P6) PPM. Alpha is dropped.
+    pub fn write_ppm<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let mut bytes = Vec::with_capacity(self.cells.len() * 3);
+        for color in &self.cells {
+            bytes.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+        writer.write_all(&bytes)
+    }
+
+    /// Writes an uncompressed 24-bit BMP. Alpha is dropped.
+    pub fn write_bmp<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        // Rows are padded to a multiple of four bytes.
+        let row_size = (self.width * 3 + 3) / 4 * 4;
+        let image_size = row_size * self.height;
+        let header_size = 14 + 40;
+
+        let mut bytes = Vec::with_capacity(header_size + image_size);
+        // BITMAPFILEHEADER
+        bytes.extend_from_slice(b"BM");
+        write_u32_le(&mut bytes, (header_size + image_size) as u32);
+        write_u32_le(&mut bytes, 0);
+        write_u32_le(&mut bytes, header_size as u32);
+        // BITMAPINFOHEADER
+        write_u32_le(&mut bytes, 40);
+        write_u32_le(&mut bytes, self.width as u32);
+        write_u32_le(&mut bytes, self.height as u32);
+        write_u16_le(&mut bytes, 1);
+        write_u16_le(&mut bytes, 24);
+        // BI_RGB, i.e. no compression
+        write_u32_le(&mut bytes, 0);
+        write_u32_le(&mut bytes, image_size as u32);
+        // 72 DPI
+        write_u32_le(&mut bytes, 2835);
+        write_u32_le(&mut bytes, 2835);
+        write_u32_le(&mut bytes, 0);
+        write_u32_le(&mut bytes, 0);
+
+        // Rows are stored bottom-up, pixels as BGR.
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let color = self.cells[y * self.width + x];
+                bytes.extend_from_slice(&[color.b, color.g, color.r]);
+            }
+            for _ in self.width * 3..row_size {
+                bytes.push(0);
+            }
+        }
+        writer.write_all(&bytes)
+    }
+
+    /// Writes an 8-bit RGBA PNG.
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&PNG_SIGNATURE)?;
+
+        let mut header = vec![];
+        write_u32_be(&mut header, self.width as u32);
+        write_u32_be(&mut header, self.height as u32);
+        // bit depth 8, color type 6 (RGBA), deflate compression, adaptive filtering,
+        // no interlacing
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_png_chunk(writer, b"IHDR", &header)?;
+
+        let stride = self.width * 4;
+        let mut filtered = Vec::with_capacity((stride + 1) * self.height);
+        let mut previous_row = vec![0; stride];
+        let mut row = vec![0; stride];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = self.cells[y * self.width + x];
+                row[x * 4..x * 4 + 4].copy_from_slice(&[color.r, color.g, color.b, color.a]);
+            }
+            filter_png_row(&row, &previous_row, 4, &mut filtered);
+            ::std::mem::swap(&mut row, &mut previous_row);
+        }
+        write_png_chunk(writer, b"IDAT", &zlib_compress(&filtered))?;
+        write_png_chunk(writer, b"IEND", &[])
+    }
+}
+
+impl Frame<f32> {
+    /// Maps a depth buffer to grayscale, with the nearest depth in the frame black and the
+    /// farthest white. Cells that were never written (non-finite or still at the clear value
+    /// of `f32::MAX`) come out white.
+    pub fn depth_to_grayscale(&self) -> Frame<Color> {
+        let written = |depth: &f32| depth.is_finite() && *depth < ::std::f32::MAX;
+        let min = self.cells.iter().cloned().filter(&written).fold(::std::f32::INFINITY, f32::min);
+        let max = self.cells.iter().cloned().filter(&written).fold(::std::f32::NEG_INFINITY, f32::max);
+        let range = if max > min { max - min } else { 1.0 };
+        let cells = self.cells.iter()
+            .map(|depth| {
+                if written(depth) {
+                    let value = ((depth - min) / range * 255.0).round() as u8;
+                    Color::RGB(value, value, value)
+                } else {
+                    Color::RGB(255, 255, 255)
+                }
+            })
+            .collect();
+        Frame{width: self.width, height: self.height, cells}
+    }
+}
+
+fn decode_png(bytes: &[u8]) -> Result<Frame<Color>, ImageError> {
+    if !bytes.starts_with(&PNG_SIGNATURE) {
+        return malformed("missing PNG signature");
+    }
+    let mut header = None;
+    let mut palette: Vec<Color> = vec![];
+    let mut transparency: Vec<u8> = vec![];
+    let mut data = vec![];
+    let mut position = PNG_SIGNATURE.len();
+    loop {
+        if position + 12 > bytes.len() {
+            return malformed("PNG is truncated");
+        }
+        let length = read_u32_be(bytes, position) as usize;
+        let chunk_type = &bytes[position + 4..position + 8];
+        if position + 12 + length > bytes.len() {
+            return malformed("PNG chunk is truncated");
+        }
+        let contents = &bytes[position + 8..position + 8 + length];
+        let crc = read_u32_be(bytes, position + 8 + length);
+        if crc != crc32_update(crc32(chunk_type), contents) {
+            return malformed("PNG chunk CRC mismatch");
+        }
+        position += 12 + length;
+        match chunk_type {
+            b"IHDR" => {
+                if length != 13 {
+                    return malformed("PNG header has the wrong length");
+                }
+                header = Some(PngHeader::parse(contents)?);
+            },
+            b"PLTE" => {
+                if length % 3 != 0 {
+                    return malformed("PNG palette length is not a multiple of 3");
+                }
+                palette = contents.chunks(3).map(|c| Color::RGB(c[0], c[1], c[2])).collect();
+            },
+            b"tRNS" => transparency = contents.to_vec(),
+            b"IDAT" => data.extend_from_slice(contents),
+            b"IEND" => break,
+            _ => {
+                // Bit 5 of the first byte marks ancillary chunks, which are safe to skip.
+                if chunk_type[0] & 0x20 == 0 {
+                    return unsupported("PNG has an unknown critical chunk");
+                }
+            },
+        }
+    }
+    let header = match header {
+        Some(header) => header,
+        None => return malformed("PNG has no header"),
+    };
+    if header.color_type == 3 && palette.is_empty() {
+        return malformed("paletted PNG has no palette");
+    }
+    let passes = if header.interlaced {
+        ADAM7_PASSES.to_vec()
+    } else {
+        vec![(0, 0, 1, 1)]
+    };
+    // The header's dimensions fix how much image data there must be, so check that before
+    // trusting them with any allocations, and don't inflate any more than that.
+    let mut expected_size = Some(0usize);
+    for &(x_start, y_start, x_step, y_step) in &passes {
+        if x_start >= header.width || y_start >= header.height {
+            continue;
+        }
+        let pass_width = (header.width - x_start + x_step - 1) / x_step;
+        let pass_height = (header.height - y_start + y_step - 1) / y_step;
+        expected_size = expected_size.and_then(|total| {
+            let stride = pass_width.checked_mul(header.bits_per_pixel())?.checked_add(7)? / 8;
+            total.checked_add((stride + 1).checked_mul(pass_height)?)
+        });
+    }
+    let expected_size = match expected_size {
+        Some(size) => size,
+        None => return malformed("PNG dimensions are too large"),
+    };
+    let raw = match zlib_decompress_limited(&data, expected_size) {
+        Ok(raw) => raw,
+        Err(message) => return malformed(message),
+    };
+    if raw.len() < expected_size {
+        return malformed("PNG image data is too short");
+    }
+
+    let mut frame = Frame::new(header.width, header.height, Color::RGBA(0, 0, 0, 0));
+    let mut offset = 0;
+    for (x_start, y_start, x_step, y_step) in passes {
+        if x_start >= header.width || y_start >= header.height {
+            continue;
+        }
+        let pass_width = (header.width - x_start + x_step - 1) / x_step;
+        let pass_height = (header.height - y_start + y_step - 1) / y_step;
+        let stride = (pass_width * header.bits_per_pixel() + 7) / 8;
+        let size = (stride + 1) * pass_height;
+        if offset + size > raw.len() {
+            return malformed("PNG image data is too short");
+        }
+        let pixels = unfilter_png(&raw[offset..offset + size], stride, header.bytes_per_pixel())?;
+        offset += size;
+        for row in 0..pass_height {
+            let row_bytes = &pixels[row * stride..(row + 1) * stride];
+            for column in 0..pass_width {
+                let color = header.pixel(row_bytes, column, &palette, &transparency);
+                frame.set(x_start + column * x_step, y_start + row * y_step, color);
+            }
+        }
+    }
+    Ok(frame)
+}
+
+struct PngHeader {
+    width: usize,
+    height: usize,
+    bit_depth: usize,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl PngHeader {
+    fn parse(contents: &[u8]) -> Result<Self, ImageError> {
+        let header = PngHeader{
+            width: read_u32_be(contents, 0) as usize,
+            height: read_u32_be(contents, 4) as usize,
+            bit_depth: contents[8] as usize,
+            color_type: contents[9],
+            interlaced: contents[12] == 1,
+        };
+        if header.width == 0 || header.height == 0 {
+            return malformed("PNG has zero size");
+        }
+        let valid_depths: &[usize] = match header.color_type {
+            0 => &[1, 2, 4, 8, 16],
+            2 | 4 | 6 => &[8, 16],
+            3 => &[1, 2, 4, 8],
+            _ => return malformed("invalid PNG color type"),
+        };
+        if !valid_depths.contains(&header.bit_depth) {
+            return malformed("invalid PNG bit depth for color type");
+        }
+        if contents[10] != 0 || contents[11] != 0 || contents[12] > 1 {
+            return unsupported("unknown PNG compression, filter or interlace method");
+        }
+        Ok(header)
+    }
+
+    fn channels(&self) -> usize {
+        match self.color_type {
+            0 | 3 => 1,
+            2 => 3,
+            4 => 2,
+            _ => 4,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.bit_depth
+    }
+
+    // The filters work on whole bytes, using at least one byte as the distance to "left".
+    fn bytes_per_pixel(&self) -> usize {
+        std::cmp::max(1, self.bits_per_pixel() / 8)
+    }
+
+    // Sample `channel` of pixel `column`, scaled down to 8 bits.
+    fn sample(&self, row: &[u8], column: usize, channel: usize) -> u8 {
+        let index = column * self.channels() + channel;
+        match self.bit_depth {
+            16 => row[index * 2],
+            8 => row[index],
+            depth => {
+                let bit = index * depth;
+                let value = (row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8;
+                if self.color_type == 3 {
+                    value
+                } else {
+                    (value as usize * 255 / ((1 << depth) - 1)) as u8
+                }
+            },
+        }
+    }
+
+    // The raw sample value, for comparing against tRNS color keys.
+    fn raw_sample(&self, row: &[u8], column: usize, channel: usize) -> u16 {
+        let index = column * self.channels() + channel;
+        match self.bit_depth {
+            16 => (row[index * 2] as u16) << 8 | row[index * 2 + 1] as u16,
+            8 => row[index] as u16,
+            depth => {
+                let bit = index * depth;
+                ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8) as u16
+            },
+        }
+    }
+
+    fn pixel(&self, row: &[u8], column: usize, palette: &Vec<Color>, transparency: &Vec<u8>) -> Color {
+        let key = |channel: usize| {
+            if transparency.len() >= channel * 2 + 2 {
+                Some((transparency[channel * 2] as u16) << 8 | transparency[channel * 2 + 1] as u16)
+            } else {
+                None
+            }
+        };
+        match self.color_type {
+            0 => {
+                let gray = self.sample(row, column, 0);
+                let alpha = if key(0) == Some(self.raw_sample(row, column, 0)) { 0 } else { 255 };
+                Color::RGBA(gray, gray, gray, alpha)
+            },
+            2 => {
+                let keyed = (0..3).all(|channel| key(channel) == Some(self.raw_sample(row, column, channel)));
+                Color::RGBA(
+                    self.sample(row, column, 0),
+                    self.sample(row, column, 1),
+                    self.sample(row, column, 2),
+                    if keyed { 0 } else { 255 },
+                )
+            },
+            3 => {
+                let index = self.sample(row, column, 0) as usize;
+                let color = palette.get(index).cloned().unwrap_or(Color::RGB(0, 0, 0));
+                Color::RGBA(color.r, color.g, color.b, transparency.get(index).cloned().unwrap_or(255))
+            },
+            4 => {
+                let gray = self.sample(row, column, 0);
+                Color::RGBA(gray, gray, gray, self.sample(row, column, 1))
+            },
+            _ => Color::RGBA(
+                self.sample(row, column, 0),
+                self.sample(row, column, 1),
+                self.sample(row, column, 2),
+                self.sample(row, column, 3),
+            ),
+        }
+    }
+}
+
+fn unfilter_png(data: &[u8], stride: usize, bytes_per_pixel: usize) -> Result<Vec<u8>, ImageError> {
+    let rows = data.len() / (stride + 1);
+    let mut pixels = vec![0; stride * rows];
+    for row in 0..rows {
+        let filter = data[row * (stride + 1)];
+        let source = &data[row * (stride + 1) + 1..(row + 1) * (stride + 1)];
+        for i in 0..stride {
+            let current = row * stride + i;
+            let left = if i >= bytes_per_pixel { pixels[current - bytes_per_pixel] } else { 0 };
+            let up = if row > 0 { pixels[current - stride] } else { 0 };
+            let up_left = if row > 0 && i >= bytes_per_pixel {
+                pixels[current - stride - bytes_per_pixel]
+            } else {
+                0
+            };
+            let predictor = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth_predictor(left, up, up_left),
+                _ => return malformed("invalid PNG filter type"),
+            };
+            pixels[current] = source[i].wrapping_add(predictor);
+        }
+    }
+    Ok(pixels)
+}
+
+fn decode_bmp(bytes: &[u8]) -> Result<Frame<Color>, ImageError> {
+    if bytes.len() < 54 || !bytes.starts_with(b"BM") {
+        return malformed("BMP is truncated or missing its signature");
+    }
+    let data_offset = read_u32_le(bytes, 10) as usize;
+    let info_size = read_u32_le(bytes, 14) as usize;
+    if info_size < 40 {
+        return unsupported("BMP uses an OS/2 header");
+    }
+    let width = read_u32_le(bytes, 18) as i32;
+    let raw_height = read_u32_le(bytes, 22) as i32;
+    let bits_per_pixel = read_u16_le(bytes, 28) as usize;
+    let compression = read_u32_le(bytes, 30);
+    if width <= 0 || raw_height == 0 {
+        return malformed("BMP has invalid dimensions");
+    }
+    let height = match raw_height.checked_abs() {
+        Some(height) => height as usize,
+        None => return malformed("BMP has invalid dimensions"),
+    };
+    let (width, top_down) = (width as usize, raw_height < 0);
+
+    // Channel masks: given in the header for BI_BITFIELDS, implied otherwise.
+    let masks = match (compression, bits_per_pixel) {
+        (0, 16) => [0x7C00, 0x03E0, 0x001F, 0],
+        (0, 32) => [0x00FF0000, 0x0000FF00, 0x000000FF, 0],
+        (3, 16) | (3, 32) => {
+            // The masks follow a 40-byte header, or are part of a larger one that may also hold
+            // an alpha mask.
+            if bytes.len() < 14 + std::cmp::max(info_size, 52) + 4 {
+                return malformed("BMP bitfield masks are truncated");
+            }
+            let alpha = if info_size >= 56 { read_u32_le(bytes, 14 + 52) } else { 0 };
+            [read_u32_le(bytes, 14 + 40), read_u32_le(bytes, 14 + 44), read_u32_le(bytes, 14 + 48), alpha]
+        },
+        (0, 1) | (0, 4) | (0, 8) | (0, 24) => [0, 0, 0, 0],
+        (1, _) | (2, _) => return unsupported("RLE-compressed BMP"),
+        _ => return unsupported("BMP bit depth or compression"),
+    };
+
+    let palette: Vec<Color> = if bits_per_pixel <= 8 {
+        let colors_used = read_u32_le(bytes, 46) as usize;
+        let count = if colors_used == 0 { 1 << bits_per_pixel } else { colors_used };
+        let start = 14 + info_size;
+        if start + count * 4 > bytes.len() {
+            return malformed("BMP palette is truncated");
+        }
+        (0..count)
+            .map(|i| {
+                let entry = &bytes[start + i * 4..start + i * 4 + 4];
+                Color::RGB(entry[2], entry[1], entry[0])
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+
+    let row_size = (width * bits_per_pixel + 31) / 32 * 4;
+    match row_size.checked_mul(height).and_then(|size| size.checked_add(data_offset)) {
+        Some(end) if end <= bytes.len() => {},
+        _ => return malformed("BMP pixel data is truncated"),
+    }
+    let mut frame = Frame::new(width, height, Color::RGB(0, 0, 0));
+    for row in 0..height {
+        let y = if top_down { row } else { height - 1 - row };
+        let row_bytes = &bytes[data_offset + row * row_size..data_offset + (row + 1) * row_size];
+        for x in 0..width {
+            let color = match bits_per_pixel {
+                1 | 4 | 8 => {
+                    let bit = x * bits_per_pixel;
+                    let index = (row_bytes[bit / 8] >> (8 - bits_per_pixel - bit % 8))
+                        & ((1u16 << bits_per_pixel) - 1) as u8;
+                    match palette.get(index as usize) {
+                        Some(color) => *color,
+                        None => return malformed("BMP palette index out of range"),
+                    }
+                },
+                24 => Color::RGB(row_bytes[x * 3 + 2], row_bytes[x * 3 + 1], row_bytes[x * 3]),
+                16 => bitfield_color(read_u16_le(row_bytes, x * 2) as u32, &masks),
+                _ => bitfield_color(read_u32_le(row_bytes, x * 4), &masks),
+            };
+            frame.set(x, y, color);
+        }
+    }
+    Ok(frame)
+}
+
+fn bitfield_color(value: u32, masks: &[u32; 4]) -> Color {
+    let channel = |mask: u32| {
+        if mask == 0 {
+            return 255;
+        }
+        let shift = mask.trailing_zeros();
+        let max = (mask >> shift) as u64;
+        (((value & mask) >> shift) as u64 * 255 / max) as u8
+    };
+    Color::RGBA(channel(masks[0]), channel(masks[1]), channel(masks[2]), channel(masks[3]))
+}
+
+fn decode_tga(bytes: &[u8]) -> Result<Frame<Color>, ImageError> {
+    if bytes.len() < 18 {
+        return malformed("TGA header is truncated");
+    }
+    let id_length = bytes[0] as usize;
+    let color_map_type = bytes[1];
+    let image_type = bytes[2];
+    let color_map_first = read_u16_le(bytes, 3) as usize;
+    let color_map_length = read_u16_le(bytes, 5) as usize;
+    let color_map_depth = bytes[7] as usize;
+    let width = read_u16_le(bytes, 12) as usize;
+    let height = read_u16_le(bytes, 14) as usize;
+    let pixel_depth = bytes[16] as usize;
+    let descriptor = bytes[17];
+    if width == 0 || height == 0 {
+        return malformed("TGA has zero size");
+    }
+    let run_length_encoded = image_type & 8 != 0;
+    let base_type = image_type & !8;
+    match (base_type, pixel_depth) {
+        (1, 8) | (3, 8) | (2, 15) | (2, 16) | (2, 24) | (2, 32) => {},
+        (1, _) | (2, _) | (3, _) => return unsupported("TGA pixel depth"),
+        _ => return unsupported("TGA image type"),
+    }
+
+    let mut position = 18 + id_length;
+    let mut color_map = vec![];
+    if color_map_type == 1 {
+        if ![15, 16, 24, 32].contains(&color_map_depth) {
+            return malformed("TGA color map has an invalid depth");
+        }
+        let entry_size = (color_map_depth + 7) / 8;
+        if position + color_map_length * entry_size > bytes.len() {
+            return malformed("TGA color map is truncated");
+        }
+        for i in 0..color_map_length {
+            color_map.push(tga_color(&bytes[position + i * entry_size..], color_map_depth));
+        }
+        position += color_map_length * entry_size;
+    } else if base_type == 1 {
+        return malformed("color-mapped TGA has no color map");
+    }
+
+    let bytes_per_pixel = (pixel_depth + 7) / 8;
+    let pixel_count = width * height;
+    // Every pixel takes at least a bit of the file, even run-length encoded, so don't reserve
+    // more than the file could hold before finding out it's truncated.
+    let mut pixels: Vec<Color> = Vec::with_capacity(std::cmp::min(pixel_count, bytes.len()));
+    {
+        let decode_pixel = |data: &[u8]| -> Result<Color, ImageError> {
+            match base_type {
+                1 => {
+                    let index = data[0] as usize;
+                    match index.checked_sub(color_map_first).and_then(|i| color_map.get(i)) {
+                        Some(color) => Ok(*color),
+                        None => malformed("TGA color map index out of range"),
+                    }
+                },
+                3 => Ok(Color::RGB(data[0], data[0], data[0])),
+                _ => Ok(tga_color(data, pixel_depth)),
+            }
+        };
+        while pixels.len() < pixel_count {
+            if run_length_encoded {
+                if position >= bytes.len() {
+                    return malformed("TGA pixel data is truncated");
+                }
+                let packet = bytes[position];
+                position += 1;
+                let count = (packet & 0x7F) as usize + 1;
+                let repeated = packet & 0x80 != 0;
+                let needed = if repeated { bytes_per_pixel } else { bytes_per_pixel * count };
+                if position + needed > bytes.len() {
+                    return malformed("TGA pixel data is truncated");
+                }
+                for i in 0..count {
+                    let start = if repeated { position } else { position + i * bytes_per_pixel };
+                    let color = decode_pixel(&bytes[start..start + bytes_per_pixel])?;
+                    if pixels.len() < pixel_count {
+                        pixels.push(color);
+                    }
+                }
+                position += needed;
+            } else {
+                if position + bytes_per_pixel > bytes.len() {
+                    return malformed("TGA pixel data is truncated");
+                }
+                pixels.push(decode_pixel(&bytes[position..position + bytes_per_pixel])?);
+                position += bytes_per_pixel;
+            }
+        }
+    }
+
+    // Pixels run bottom-up and left-to-right unless the descriptor says otherwise.
+    let right_to_left = descriptor & 0x10 != 0;
+    let top_down = descriptor & 0x20 != 0;
+    let mut frame = Frame::new(width, height, Color::RGB(0, 0, 0));
+    for (i, color) in pixels.into_iter().enumerate() {
+        let (column, row) = (i % width, i / width);
+        let x = if right_to_left { width - 1 - column } else { column };
+        let y = if top_down { row } else { height - 1 - row };
+        frame.set(x, y, color);
+    }
+    Ok(frame)
+}
+
+fn tga_color(data: &[u8], depth: usize) -> Color {
+    match depth {
+        15 | 16 => {
+            let value = read_u16_le(data, 0);
+            let channel = |shift: u16| ((((value >> shift) & 0x1F) as u32 * 255) / 31) as u8;
+            // The top bit is an attribute bit that most writers leave clear, so it's not
+            // treated as alpha.
+            Color::RGB(channel(10), channel(5), channel(0))
+        },
+        24 => Color::RGB(data[2], data[1], data[0]),
+        _ => Color::RGBA(data[2], data[1], data[0], data[3]),
+    }
+}
+
+fn decode_ppm(bytes: &[u8]) -> Result<Frame<Color>, ImageError> {
+    let mut position = 0;
+    let magic = match next_ppm_token(bytes, &mut position) {
+        Some(token) => token,
+        None => return malformed("PPM is empty"),
+    };
+    let (binary, channels) = match magic {
+        b"P2" => (false, 1),
+        b"P3" => (false, 3),
+        b"P5" => (true, 1),
+        b"P6" => (true, 3),
+        _ => return unsupported("only P2, P3, P5 and P6 netpbm images are supported"),
+    };
+    let mut header = [0; 3];
+    for value in header.iter_mut() {
+        *value = match next_ppm_token(bytes, &mut position).and_then(parse_ppm_number) {
+            Some(number) => number,
+            None => return malformed("PPM header is invalid"),
+        };
+    }
+    let (width, height, max_value) = (header[0], header[1], header[2]);
+    if width == 0 || height == 0 || max_value == 0 || max_value > 65535 {
+        return malformed("PPM header values are out of range");
+    }
+
+    let sample_count = match width.checked_mul(height).and_then(|pixels| pixels.checked_mul(channels)) {
+        Some(count) => count,
+        None => return malformed("PPM dimensions are too large"),
+    };
+    // Every sample takes at least a byte, so a header can't make us reserve more than that.
+    let mut samples = Vec::with_capacity(std::cmp::min(sample_count, bytes.len()));
+    if binary {
+        // Exactly one whitespace byte separates the header from the raster.
+        position += 1;
+        let sample_size = if max_value > 255 { 2 } else { 1 };
+        match sample_count.checked_mul(sample_size).and_then(|size| size.checked_add(position)) {
+            Some(end) if end <= bytes.len() => {},
+            _ => return malformed("PPM pixel data is truncated"),
+        }
+        for i in 0..sample_count {
+            let start = position + i * sample_size;
+            samples.push(if sample_size == 2 {
+                (bytes[start] as usize) << 8 | bytes[start + 1] as usize
+            } else {
+                bytes[start] as usize
+            });
+        }
+    } else {
+        for _ in 0..sample_count {
+            match next_ppm_token(bytes, &mut position).and_then(parse_ppm_number) {
+                Some(sample) => samples.push(sample),
+                None => return malformed("PPM pixel data is truncated or invalid"),
+            }
+        }
+    }
+
+    let scale = |sample: usize| (std::cmp::min(sample, max_value) * 255 / max_value) as u8;
+    let cells = samples.chunks(channels)
+        .map(|pixel| {
+            if channels == 1 {
+                Color::RGB(scale(pixel[0]), scale(pixel[0]), scale(pixel[0]))
+            } else {
+                Color::RGB(scale(pixel[0]), scale(pixel[1]), scale(pixel[2]))
+            }
+        })
+        .collect();
+    Ok(Frame{width, height, cells})
+}
+
+// Header tokens are separated by whitespace, and comments run from '#' to the end of the line.
+fn next_ppm_token<'a>(bytes: &'a [u8], position: &mut usize) -> Option<&'a [u8]> {
+    loop {
+        while *position < bytes.len() && (bytes[*position] as char).is_whitespace() {
+            *position += 1;
+        }
+        if *position < bytes.len() && bytes[*position] == b'#' {
+            while *position < bytes.len() && bytes[*position] != b'\n' {
+                *position += 1;
+            }
+        } else {
+            break;
+        }
+    }
+    let start = *position;
+    while *position < bytes.len() && !(bytes[*position] as char).is_whitespace() {
+        *position += 1;
+    }
+    if start == *position {
+        None
+    } else {
+        Some(&bytes[start..*position])
+    }
+}
+
+fn parse_ppm_number(token: &[u8]) -> Option<usize> {
+    std::str::from_utf8(token).ok().and_then(|s| s.parse().ok())
+}
+
+// Picks whichever of the five PNG filters gives the smallest sum of absolute residuals, the
+// heuristic recommended by the PNG specification.
+fn filter_png_row(row: &[u8], previous_row: &[u8], bytes_per_pixel: usize, output: &mut Vec<u8>) {
+    let mut best_filter = 0;
+    let mut best_row = row.to_vec();
+    let mut best_score = u64::max_value();
+    for filter in 0..5 {
+        let candidate: Vec<u8> = (0..row.len())
+            .map(|i| {
+                let left = if i >= bytes_per_pixel { row[i - bytes_per_pixel] } else { 0 };
+                let up = previous_row[i];
+                let up_left = if i >= bytes_per_pixel { previous_row[i - bytes_per_pixel] } else { 0 };
+                let predictor = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    _ => paeth_predictor(left, up, up_left),
+                };
+                row[i].wrapping_sub(predictor)
+            })
+            .collect();
+        let score = candidate.iter().map(|b| (*b as i8 as i16).abs() as u64).sum();
+        if score < best_score {
+            best_score = score;
+            best_filter = filter;
+            best_row = candidate;
+        }
+    }
+    output.push(best_filter);
+    output.extend_from_slice(&best_row);
+}
+
+pub fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn write_png_chunk<W: Write>(writer: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(data.len() + 12);
+    write_u32_be(&mut bytes, data.len() as u32);
+    bytes.extend_from_slice(chunk_type);
+    bytes.extend_from_slice(data);
+    write_u32_be(&mut bytes, crc32_update(crc32(chunk_type), data));
+    writer.write_all(&bytes)
+}
+
+fn read_u16_le(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> u32 {
+    read_u16_le(bytes, offset) as u32 | (read_u16_le(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u32_be(bytes: &[u8], offset: usize) -> u32 {
+    (bytes[offset] as u32) << 24 | (bytes[offset + 1] as u32) << 16
+        | (bytes[offset + 2] as u32) << 8 | bytes[offset + 3] as u32
+}
+
+fn write_u16_le(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn write_u32_le(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+fn write_u32_be(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_ppm() {
+        let frame = Frame::new(2, 1, Color::RGB(1, 2, 3));
+        let mut bytes = vec![];
+        frame.write_ppm(&mut bytes).unwrap();
+        assert_eq!(bytes, b"P6\n2 1\n255\n\x01\x02\x03\x01\x02\x03".to_vec());
+    }
+
+    #[test]
+    fn test_write_bmp_pads_rows() {
+        let frame = Frame::new(1, 2, Color::RGB(1, 2, 3));
+        let mut bytes = vec![];
+        frame.write_bmp(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 54 + 2 * 4);
+        assert_eq!(&bytes[54..], &[3, 2, 1, 0, 3, 2, 1, 0]);
+    }
+
+    fn checkerboard() -> Frame<Color> {
+        let mut frame = Frame::new(5, 3, Color::RGBA(10, 20, 30, 40));
+        for x in 0..5 {
+            for y in 0..3 {
+                if (x + y) % 2 == 0 {
+                    frame.set(x, y, Color::RGBA(200, 150, 100, 255));
+                }
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let frame = checkerboard();
+        let mut bytes = vec![];
+        frame.write_png(&mut bytes).unwrap();
+        assert_eq!(Frame::decode(&bytes, ImageFormat::Png).unwrap().cells(), frame.cells());
+    }
+
+    #[test]
+    fn test_bmp_round_trip() {
+        let mut frame = checkerboard();
+        frame.set_all(Color::RGB(1, 2, 3));
+        frame.set(4, 0, Color::RGB(4, 5, 6));
+        let mut bytes = vec![];
+        frame.write_bmp(&mut bytes).unwrap();
+        assert_eq!(Frame::decode(&bytes, ImageFormat::Bmp).unwrap().cells(), frame.cells());
+    }
+
+    #[test]
+    fn test_decode_paletted_png() {
+        // A 3x1 image with 2-bit palette indices 2, 0, 1 and a transparent first entry.
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        write_png_chunk(&mut bytes, b"IHDR", &[0, 0, 0, 3, 0, 0, 0, 1, 2, 3, 0, 0, 0]).unwrap();
+        write_png_chunk(&mut bytes, b"PLTE", &[255, 0, 0, 0, 255, 0, 0, 0, 255]).unwrap();
+        write_png_chunk(&mut bytes, b"tRNS", &[0]).unwrap();
+        write_png_chunk(&mut bytes, b"IDAT", &zlib_compress(&[0, 0b10_00_01_00])).unwrap();
+        write_png_chunk(&mut bytes, b"IEND", &[]).unwrap();
+        assert_eq!(Frame::decode(&bytes, ImageFormat::Png).unwrap().cells(), &vec![
+            Color::RGB(0, 0, 255),
+            Color::RGBA(255, 0, 0, 0),
+            Color::RGB(0, 255, 0),
+        ]);
+    }
+
+    #[test]
+    fn test_decode_16_bit_png() {
+        // 2x1 RGBA with 16-bit samples, which are cut down to their high bytes.
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        write_png_chunk(&mut bytes, b"IHDR", &[0, 0, 0, 2, 0, 0, 0, 1, 16, 6, 0, 0, 0]).unwrap();
+        let row = [0, 0x12, 0x34, 0xAB, 0xCD, 0x00, 0xFF, 0x80, 0x00, 0xFF, 0xFF, 0x00, 0x01, 0x7F, 0xFF, 0xFF, 0xFF];
+        write_png_chunk(&mut bytes, b"IDAT", &zlib_compress(&row)).unwrap();
+        write_png_chunk(&mut bytes, b"IEND", &[]).unwrap();
+        assert_eq!(Frame::decode(&bytes, ImageFormat::Png).unwrap().cells(), &vec![
+            Color::RGBA(0x12, 0xAB, 0x00, 0x80),
+            Color::RGBA(0xFF, 0x00, 0x7F, 0xFF),
+        ]);
+    }
+
+    fn assert_malformed(bytes: &[u8], format: ImageFormat) {
+        match Frame::decode(bytes, format) {
+            Err(ImageError::Malformed(_)) => {},
+            other => panic!("expected a malformed image error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    // The file and info headers of a 1x1 BMP, with room for nothing else.
+    fn bmp_header(info_size: u32, height: u32, bits_per_pixel: u16, compression: u32) -> Vec<u8> {
+        let mut bytes = b"BM".to_vec();
+        bytes.resize(54, 0);
+        bytes[10] = 54;
+        bytes[14..18].copy_from_slice(&[info_size as u8, (info_size >> 8) as u8, 0, 0]);
+        bytes[18] = 1;
+        bytes[22..26].copy_from_slice(&[height as u8, (height >> 8) as u8, (height >> 16) as u8, (height >> 24) as u8]);
+        bytes[28..30].copy_from_slice(&[bits_per_pixel as u8, (bits_per_pixel >> 8) as u8]);
+        bytes[30] = compression as u8;
+        bytes
+    }
+
+    #[test]
+    fn test_decoders_reject_malformed_headers() {
+        // A height of i32::MIN has no absolute value.
+        assert_malformed(&bmp_header(40, 0x80000000, 24, 0), ImageFormat::Bmp);
+        // A bitfield BMP whose header claims an alpha mask that isn't there.
+        let mut bitfields = bmp_header(56, 1, 32, 3);
+        bitfields.resize(66, 0);
+        assert_malformed(&bitfields, ImageFormat::Bmp);
+        // Dimensions whose sample count overflows, or is far larger than the file.
+        assert_malformed(b"P6\n4294967296 4294967296 255\n", ImageFormat::Ppm);
+        assert_malformed(b"P6\n100000 100000 255\n\x00\x00\x00", ImageFormat::Ppm);
+        // A color-mapped TGA whose map entries are 8 bits, which isn't a color.
+        let mut tga = vec![0, 1, 1, 0, 0, 1, 0, 8, 0, 0, 0, 0, 1, 0, 1, 0, 8, 0];
+        tga.extend_from_slice(&[255, 0]);
+        assert_malformed(&tga, ImageFormat::Tga);
+        // A 65535x65535 32-bit TGA with no pixel data.
+        assert_malformed(&[0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 32, 0], ImageFormat::Tga);
+        // A 65535x65535 PNG whose image data is a few bytes, and a 1x1 one whose data inflates
+        // to a megabyte.
+        let png = |size: u16, data: &[u8]| {
+            let (high, low) = ((size >> 8) as u8, size as u8);
+            let mut bytes = PNG_SIGNATURE.to_vec();
+            write_png_chunk(&mut bytes, b"IHDR", &[0, 0, high, low, 0, 0, high, low, 8, 6, 0, 0, 0]).unwrap();
+            write_png_chunk(&mut bytes, b"IDAT", &zlib_compress(data)).unwrap();
+            write_png_chunk(&mut bytes, b"IEND", &[]).unwrap();
+            bytes
+        };
+        assert_malformed(&png(65535, &[0, 1, 2, 3, 4]), ImageFormat::Png);
+        assert_malformed(&png(1, &vec![0; 1 << 20]), ImageFormat::Png);
+        assert!(Frame::decode(&png(1, &[0, 1, 2, 3, 4]), ImageFormat::Png).is_ok());
+    }
+
+    #[test]
+    fn test_decode_png_rejects_bad_crc() {
+        let mut bytes = vec![];
+        checkerboard().write_png(&mut bytes).unwrap();
+        bytes[20] ^= 1;
+        match Frame::decode(&bytes, ImageFormat::Png) {
+            Err(ImageError::Malformed(_)) => {},
+            other => panic!("expected a malformed image error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_decode_run_length_encoded_tga() {
+        // 3x1, 24-bit, top-down: one run of two red pixels, then one raw blue pixel.
+        let mut bytes = vec![0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 1, 0, 24, 0x20];
+        bytes.extend_from_slice(&[0x81, 0, 0, 255, 0x00, 255, 0, 0]);
+        assert_eq!(Frame::decode(&bytes, ImageFormat::Tga).unwrap().cells(), &vec![
+            Color::RGB(255, 0, 0),
+            Color::RGB(255, 0, 0),
+            Color::RGB(0, 0, 255),
+        ]);
+    }
+
+    #[test]
+    fn test_decode_ascii_ppm() {
+        let bytes = b"P3\n# a comment\n2 1\n15\n15 0 0  0 0 15\n";
+        assert_eq!(Frame::decode(bytes, ImageFormat::Ppm).unwrap().cells(), &vec![
+            Color::RGB(255, 0, 0),
+            Color::RGB(0, 0, 255),
+        ]);
+    }
+
+    #[test]
+    fn test_depth_to_grayscale() {
+        let mut depth = Frame::new(3, 1, ::std::f32::MAX);
+        depth.set(0, 0, 0.5);
+        depth.set(1, 0, 1.0);
+        let gray = depth.depth_to_grayscale();
+        assert_eq!(gray.cells(), &vec![
+            Color::RGB(0, 0, 0),
+            Color::RGB(255, 255, 255),
+            Color::RGB(255, 255, 255),
+        ]);
+    }
+}
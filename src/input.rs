@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+#[cfg(feature = "sdl")]
+use sdl2::event::Event;
+#[cfg(feature = "sdl")]
+use sdl2::keyboard::Scancode;
+#[cfg(feature = "sdl")]
+use sdl2::mouse;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    W,
+    A,
+    S,
+    D,
+    Q,
+    E,
+    Up,
+    Down,
+    Left,
+    Right,
+    Space,
+    Shift,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+/// Keys and mouse buttons currently held, plus mouse movement accumulated since the last call
+/// to `end_frame`. Windowing code feeds it events; camera controllers read from it.
+#[derive(Clone, Debug)]
+pub struct InputState {
+    keys: HashSet<Key>,
+    buttons: HashSet<MouseButton>,
+    /// Mouse movement in pixels, with y increasing downwards.
+    pub mouse_delta: (f32, f32),
+    /// Scroll wheel movement, positive away from the user.
+    pub scroll: f32,
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        InputState{
+            keys: HashSet::new(),
+            buttons: HashSet::new(),
+            mouse_delta: (0.0, 0.0),
+            scroll: 0.0,
+        }
+    }
+
+    pub fn set_key(&mut self, key: Key, pressed: bool) {
+        if pressed {
+            self.keys.insert(key);
+        } else {
+            self.keys.remove(&key);
+        }
+    }
+
+    pub fn is_key_pressed(&self, key: Key) -> bool {
+        self.keys.contains(&key)
+    }
+
+    pub fn set_button(&mut self, button: MouseButton, pressed: bool) {
+        if pressed {
+            self.buttons.insert(button);
+        } else {
+            self.buttons.remove(&button);
+        }
+    }
+
+    pub fn is_button_pressed(&self, button: MouseButton) -> bool {
+        self.buttons.contains(&button)
+    }
+
+    pub fn move_mouse(&mut self, dx: f32, dy: f32) {
+        self.mouse_delta.0 += dx;
+        self.mouse_delta.1 += dy;
+    }
+
+    pub fn scroll(&mut self, amount: f32) {
+        self.scroll += amount;
+    }
+
+    /// Clears per-frame movement; held keys and buttons stay held.
+    pub fn end_frame(&mut self) {
+        self.mouse_delta = (0.0, 0.0);
+        self.scroll = 0.0;
+    }
+
+    #[cfg(feature = "sdl")]
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::KeyDown{scancode: Some(scancode), ..} => {
+                if let Some(key) = key_for(scancode) {
+                    self.set_key(key, true);
+                }
+            },
+            Event::KeyUp{scancode: Some(scancode), ..} => {
+                if let Some(key) = key_for(scancode) {
+                    self.set_key(key, false);
+                }
+            },
+            Event::MouseButtonDown{mouse_btn, ..} => {
+                if let Some(button) = button_for(mouse_btn) {
+                    self.set_button(button, true);
+                }
+            },
+            Event::MouseButtonUp{mouse_btn, ..} => {
+                if let Some(button) = button_for(mouse_btn) {
+                    self.set_button(button, false);
+                }
+            },
+            Event::MouseMotion{xrel, yrel, ..} => self.move_mouse(xrel as f32, yrel as f32),
+            Event::MouseWheel{y, ..} => self.scroll(y as f32),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn key_for(scancode: Scancode) -> Option<Key> {
+    match scancode {
+        Scancode::W => Some(Key::W),
+        Scancode::A => Some(Key::A),
+        Scancode::S => Some(Key::S),
+        Scancode::D => Some(Key::D),
+        Scancode::Q => Some(Key::Q),
+        Scancode::E => Some(Key::E),
+        Scancode::Up => Some(Key::Up),
+        Scancode::Down => Some(Key::Down),
+        Scancode::Left => Some(Key::Left),
+        Scancode::Right => Some(Key::Right),
+        Scancode::Space => Some(Key::Space),
+        Scancode::LShift | Scancode::RShift => Some(Key::Shift),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn button_for(button: mouse::MouseButton) -> Option<MouseButton> {
+    match button {
+        mouse::MouseButton::Left => Some(MouseButton::Left),
+        mouse::MouseButton::Middle => Some(MouseButton::Middle),
+        mouse::MouseButton::Right => Some(MouseButton::Right),
+        _ => None,
+    }
+}
@@ -1,16 +1,29 @@
 extern crate cgmath;
 extern crate collision;
 extern crate num_traits;
+#[cfg(feature = "sdl")]
 extern crate sdl2;
 
 pub mod camera;
 pub mod colors;
+pub mod controllers;
+pub mod deflate;
 pub mod frame;
 pub mod geometry;
+pub mod images;
+pub mod input;
 pub mod light;
 pub mod materials;
 pub mod math;
+pub mod obj;
+pub mod output;
+pub mod picking;
 pub mod rasterizer;
 pub mod render;
+#[cfg(feature = "sdl")]
 pub mod sdl_utils;
+pub mod shaders;
+pub mod shadows;
+pub mod targets;
 pub mod textures;
+pub mod thread_pool;
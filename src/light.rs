@@ -11,6 +11,8 @@ pub struct Light {
     pub light_type: LightType,
     pub color: FloatColor,
     pub intensity: f32,
+    /// Whether the renderer builds a shadow map for this light.
+    pub casts_shadows: bool,
 }
 
 impl Light {
@@ -18,9 +20,11 @@ impl Light {
         Light{
             light_type: LightType::Point(PointLight{
                 position: pos,
+                attenuation: Attenuation::inverse_square(),
             }),
             color: FloatColor::from_rgb(1.0, 1.0, 1.0),
             intensity: 1.0,
+            casts_shadows: false,
         }
     }
 
@@ -31,20 +35,178 @@ impl Light {
             }),
             color: FloatColor::from_rgb(1.0, 1.0, 1.0),
             intensity: 1.0,
+            casts_shadows: false,
         }
     }
+
+    /// A light at `pos` shining along `dir`. Surfaces within `inner` of the axis are fully lit,
+    /// and the light fades out smoothly between `inner` and `outer`.
+    pub fn spot_light<R: Into<Rad<f32>>>(pos: Vector3<f32>, dir: Vector3<f32>, inner: R, outer: R) -> Self {
+        Light{
+            light_type: LightType::Spot(SpotLight{
+                position: pos,
+                direction: dir / dir.magnitude(),
+                inner_angle: inner.into(),
+                outer_angle: outer.into(),
+                attenuation: Attenuation::inverse_square(),
+            }),
+            color: FloatColor::from_rgb(1.0, 1.0, 1.0),
+            intensity: 1.0,
+            casts_shadows: false,
+        }
+    }
+
+    pub fn with_color(mut self, color: FloatColor) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_shadows(mut self, casts_shadows: bool) -> Self {
+        self.casts_shadows = casts_shadows;
+        self
+    }
+
+    /// Sets the distance falloff of point and spot lights; directional lights don't fall off.
+    pub fn with_attenuation(mut self, attenuation: Attenuation) -> Self {
+        match self.light_type {
+            LightType::Point(ref mut point_light) => point_light.attenuation = attenuation,
+            LightType::Spot(ref mut spot_light) => spot_light.attenuation = attenuation,
+            LightType::Directional(_) => {},
+        }
+        self
+    }
+
+    /// The unit direction from `world_position` towards the light, and how strongly the light
+    /// reaches that point once intensity, distance and cone falloff are accounted for. A point
+    /// exactly at a point or spot light's position has no direction to it, and gets a zero
+    /// direction and no light.
+    pub fn incidence(&self, world_position: &Vector3<f32>) -> (Vector3<f32>, f32) {
+        let (direction, falloff) = match self.light_type {
+            LightType::Directional(ref directional_light) => (directional_light.direction, 1.0),
+            LightType::Point(ref point_light) => {
+                let ray = point_light.position - world_position;
+                let distance = ray.magnitude();
+                if distance == 0.0 {
+                    return (Vector3::zero(), 0.0);
+                }
+                (ray / distance, point_light.attenuation.at(distance))
+            },
+            LightType::Spot(ref spot_light) => {
+                let ray = spot_light.position - world_position;
+                let distance = ray.magnitude();
+                if distance == 0.0 {
+                    return (Vector3::zero(), 0.0);
+                }
+                let direction = ray / distance;
+                let cone = smoothstep(
+                    spot_light.outer_angle.cos(),
+                    spot_light.inner_angle.cos(),
+                    -direction.dot(spot_light.direction),
+                );
+                (direction, cone * spot_light.attenuation.at(distance))
+            },
+        };
+        (direction, falloff * self.intensity)
+    }
 }
 
 pub enum LightType {
     Point(PointLight),
     Directional(DirectionalLight),
+    Spot(SpotLight),
 }
 
 pub struct PointLight {
     pub position: Vector3<f32>,
+    pub attenuation: Attenuation,
 }
 
 pub struct DirectionalLight {
     pub direction: Vector3<f32>,
 }
 
+pub struct SpotLight {
+    pub position: Vector3<f32>,
+    /// The direction the light shines in.
+    pub direction: Vector3<f32>,
+    pub inner_angle: Rad<f32>,
+    pub outer_angle: Rad<f32>,
+    pub attenuation: Attenuation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Attenuation {
+    /// 1 / (constant + linear * distance + quadratic * distance²).
+    Polynomial{constant: f32, linear: f32, quadratic: f32},
+    /// Falls off smoothly from full strength to nothing at the given distance.
+    Range(f32),
+}
+
+impl Attenuation {
+    pub fn inverse_square() -> Self {
+        Attenuation::Polynomial{constant: 0.0, linear: 0.0, quadratic: 1.0}
+    }
+
+    pub fn at(&self, distance: f32) -> f32 {
+        match *self {
+            Attenuation::Polynomial{constant, linear, quadratic} => {
+                1.0 / (constant + linear * distance + quadratic * distance * distance)
+            },
+            Attenuation::Range(range) => {
+                let ratio = distance / range;
+                let window = (1.0 - ratio * ratio).max(0.0);
+                window * window
+            },
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge0 == edge1 {
+        return if x < edge0 { 0.0 } else { 1.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attenuation() {
+        assert_eq!(Attenuation::inverse_square().at(2.0), 0.25);
+        let linear = Attenuation::Polynomial{constant: 1.0, linear: 1.0, quadratic: 0.0};
+        assert_eq!(linear.at(3.0), 0.25);
+        assert_eq!(Attenuation::Range(4.0).at(0.0), 1.0);
+        assert_eq!(Attenuation::Range(4.0).at(5.0), 0.0);
+        assert!(Attenuation::Range(4.0).at(2.0) < 1.0);
+    }
+
+    #[test]
+    fn test_spot_light_cone() {
+        let light = Light::spot_light(
+            Vector3{x: 0.0, y: 1.0, z: 0.0},
+            Vector3{x: 0.0, y: -1.0, z: 0.0},
+            Deg(20.0),
+            Deg(40.0),
+        ).with_attenuation(Attenuation::Polynomial{constant: 1.0, linear: 0.0, quadratic: 0.0}).with_intensity(2.0);
+        let under = light.incidence(&Vector3{x: 0.0, y: 0.0, z: 0.0});
+        assert_eq!(under.0, Vector3{x: 0.0, y: 1.0, z: 0.0});
+        assert!((under.1 - 2.0).abs() < 0.001);
+        // The falloff is a smoothstep in the cosine of the angle off axis, so it's at half
+        // strength where that cosine is halfway between the inner and outer ones.
+        let halfway = ((Deg(20.0f32).cos() + Deg(40.0f32).cos()) / 2.0).acos();
+        let edge = light.incidence(&Vector3{x: halfway.tan(), y: 0.0, z: 0.0}).1;
+        assert!((edge - 1.0).abs() < 1e-4, "{}", edge);
+        assert_eq!(light.incidence(&Vector3{x: 1.0, y: 0.0, z: 0.0}).1, 0.0);
+        assert_eq!(light.incidence(&Vector3{x: 0.0, y: 1.0, z: 0.0}), (Vector3::zero(), 0.0));
+        let point = Light::point_light(Vector3{x: 1.0, y: 2.0, z: 3.0});
+        assert_eq!(point.incidence(&Vector3{x: 1.0, y: 2.0, z: 3.0}), (Vector3::zero(), 0.0));
+    }
+}
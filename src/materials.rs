@@ -6,6 +6,11 @@ pub struct Material {
     pub diffuse: FloatColor,
     pub ambient: FloatColor,
     pub specular: FloatColor,
+    /// Specular exponent; higher values give smaller, sharper highlights.
+    pub shininess: f32,
+    /// Multiplies the alpha of the texture; only visible with a blend mode other than
+    /// `BlendMode::Opaque`.
+    pub opacity: f32,
     // This isn't good, because it enforces ideas on Material that should be flexible across
     // different fragment processors, namely that a material is associated with one particular
     // texture.
@@ -18,6 +23,8 @@ impl Material {
             diffuse: FloatColor::from_rgb(1.0, 1.0, 1.0),
             ambient: FloatColor::from_rgb(1.0, 1.0, 1.0),
             specular: FloatColor::from_rgb(1.0, 1.0, 1.0),
+            shininess: 32.0,
+            opacity: 1.0,
             texture: None,
         }
     }
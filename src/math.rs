@@ -10,18 +10,15 @@ use cgmath::{
     Vector3,
     Vector4,
     Point3,
-    SquareMatrix,
-    Matrix4,
 };
 use std;
-use sdl2::pixels::Color;
+use colors::Color;
 use std::cmp::max;
 use std::cmp::min;
 use collision::Ray;
 use collision::Line;
 use collision::Continuous;
 use collision::Plane;
-use geometry::Vertex4;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct RectBounds<T> {
@@ -199,30 +196,56 @@ pub fn mix_colors(colors: &Vec<Color>, weights: &Vec<f32>) -> Color {
     return Color{a: 255, r, g, b};
 }
 
-pub fn homogenous_intersection<T>(p0: Vector4<T>, p1: Vector4<T>, plane: &Fn(Vector4<T>) -> T) -> Option<Vector4<T>> where T: BaseFloat {
-    let b0 = p0.w + plane(p0);
-    let b1 = p1.w + plane(p1);
+/// Something with a position in homogenous clip space that can be clipped, i.e. split at an
+/// arbitrary point along the segment between two instances.
+pub trait Homogenous<T>: Copy {
+    fn homogenous_position(&self) -> Vector4<T>;
+
+    fn lerp(&self, other: &Self, t: T) -> Self;
+}
+
+impl <T> Homogenous<T> for Vector4<T> where T: BaseFloat {
+    fn homogenous_position(&self) -> Vector4<T> {
+        *self
+    }
+
+    fn lerp(&self, other: &Self, t: T) -> Self {
+        *self * (T::one() - t) + *other * t
+    }
+}
+
+pub fn homogenous_intersection<T, P>(p0: P, p1: P, plane: &Fn(Vector4<T>) -> T) -> Option<P>
+    where T: BaseFloat, P: Homogenous<T> {
+    let h0 = p0.homogenous_position();
+    let h1 = p1.homogenous_position();
+    let b0 = h0.w + plane(h0);
+    let b1 = h1.w + plane(h1);
     let t = b0 / (b0 - b1);
     if t < T::zero() || t > T::one() {
         None
     } else {
-        Some(p0 * (T::one() - t) + p1 * t)
+        Some(p0.lerp(&p1, t))
     }
 }
 
 // vertices are kept if they're on the side of the plane indicated by the normal vector
-pub fn clip<T>(points: &Vec<Vector4<T>>, plane: &Fn(Vector4<T>) -> T) -> Vec<Vector4<T>> where T: BaseFloat {
+pub fn clip<T, P>(points: &Vec<P>, plane: &Fn(Vector4<T>) -> T) -> Vec<P>
+    where T: BaseFloat, P: Homogenous<T> {
     if points.len() == 0 {
         return vec![];
     }
-    let mut new_points: Vec<Vector4<T>> = vec![];
-    let mut above = points[points.len() - 1].w + plane(points[points.len() - 1]) >= T::zero();
+    let inside = |p: &P| {
+        let h = p.homogenous_position();
+        h.w + plane(h) >= T::zero()
+    };
+    let mut new_points: Vec<P> = vec![];
+    let mut above = inside(&points[points.len() - 1]);
     for (i, point) in points.iter().enumerate() {
         let prev_point = match i {
             0 => points[points.len() - 1],
             _ => points[i - 1],
         };
-        if (*point).w + plane(*point) >= T::zero() {
+        if inside(point) {
             if above == false {
                 // crossed back over!
                 let intersection = homogenous_intersection(prev_point, *point, plane).unwrap();
@@ -247,15 +270,25 @@ pub fn clip<T>(points: &Vec<Vector4<T>>, plane: &Fn(Vector4<T>) -> T) -> Vec<Vec
     new_points
 }
 
-// Remember that if we ever add vertex attributes of any kind they'll need to be interpolated
-// while clipping
-pub fn clip_in_box<T>(points: &Vec<Vector4<T>>) -> Vec<Vector4<T>> where T: BaseFloat {
-    let mut clipped_points = clip(points, &|p: Vector4<T>| p.x);
-    clipped_points = clip(&clipped_points, &|p: Vector4<T>| -p.x);
-    clipped_points = clip(&clipped_points, &|p: Vector4<T>| p.y);
-    clipped_points = clip(&clipped_points, &|p: Vector4<T>| -p.y);
-    clipped_points = clip(&clipped_points, &|p: Vector4<T>| p.z);
-    clipped_points = clip(&clipped_points, &|p: Vector4<T>| -p.z);
+// The six planes bounding the view volume, -w <= x, y, z <= w, as used by `clip`.
+fn clip_planes<T>() -> [fn(Vector4<T>) -> T; 6] where T: BaseFloat {
+    [
+        |p: Vector4<T>| p.x,
+        |p: Vector4<T>| -p.x,
+        |p: Vector4<T>| p.y,
+        |p: Vector4<T>| -p.y,
+        |p: Vector4<T>| p.z,
+        |p: Vector4<T>| -p.z,
+    ]
+}
+
+// Anything carried along with the points (vertex attributes, varyings) is interpolated by
+// Homogenous::lerp as the polygon is cut.
+pub fn clip_in_box<T, P>(points: &Vec<P>) -> Vec<P> where T: BaseFloat, P: Homogenous<T> {
+    let mut clipped_points = points.clone();
+    for plane in clip_planes().iter() {
+        clipped_points = clip(&clipped_points, plane);
+    }
     clipped_points
 }
 
@@ -290,41 +323,39 @@ pub fn barycentric_coordinates4<T>(
     return (p0_coordinate, p1_coordinate, p2_coordinate);
 }
 
-pub fn interpolate_vertex4(
-    (v0, v1, v2): (Vertex4, Vertex4, Vertex4),
-    v: Vector4<f32>,
-) -> Vertex4 {
-    let (b0, b1, b2) = barycentric_coordinates4(
-        (v0.position, v1.position, v2.position),
-        v,
-    );
-    Vertex4{
-        position: v,
-        uv: v0.uv * b0 + v1.uv * b1  + v2.uv * b2,
-        normal: v0.normal * b0 + v1.normal * b1 + v2.normal * b2,
+pub fn clip_triangle<T, P>(v0: P, v1: P, v2: P) -> Vec<(P, P, P)>
+    where T: BaseFloat, P: Homogenous<T> {
+    let points = clip_in_box(&vec![v0, v1, v2]);
+    convex_triangulation(&points)
+}
+
+// The two-point version of `clip`, or None if the whole segment is outside.
+fn clip_segment<T, P>(p0: P, p1: P, plane: &dyn Fn(Vector4<T>) -> T) -> Option<(P, P)>
+    where T: BaseFloat, P: Homogenous<T> {
+    let inside = |p: &P| {
+        let h = p.homogenous_position();
+        h.w + plane(h) >= T::zero()
+    };
+    match (inside(&p0), inside(&p1)) {
+        (true, true) => Some((p0, p1)),
+        (false, false) => None,
+        (true, false) => Some((p0, homogenous_intersection(p0, p1, plane).unwrap_or(p0))),
+        (false, true) => Some((homogenous_intersection(p0, p1, plane).unwrap_or(p1), p1)),
+    }
+}
+
+/// Clips a line segment to the view volume, or returns None if none of it is inside.
+pub fn clip_line<T, P>(p0: P, p1: P) -> Option<(P, P)> where T: BaseFloat, P: Homogenous<T> {
+    let mut segment = (p0, p1);
+    for plane in clip_planes().iter() {
+        segment = clip_segment(segment.0, segment.1, plane)?;
     }
+    Some(segment)
 }
 
-pub fn clip_triangle(
-    v0: Vertex4,
-    v1: Vertex4,
-    v2: Vertex4,
-    // For proper vertex attribute interpolation. There's probably a better way to do this.
-    clip_to_camera_matrix: Matrix4<f32>,
-) -> Vec<(Vertex4, Vertex4, Vertex4)> {
-    let points = clip_in_box(&vec![v0.position, v1.position, v2.position]);
-    let w0 = v0.transformed(clip_to_camera_matrix);
-    let w1 = v1.transformed(clip_to_camera_matrix);
-    let w2 = v2.transformed(clip_to_camera_matrix);
-    let vertices = points.iter()
-        .map(|v| {
-            let w = clip_to_camera_matrix * v;
-            interpolate_vertex4((w0, w1, w2), w)
-                .transformed(clip_to_camera_matrix.invert().unwrap())
-        })
-        .collect();
-    let triangles = convex_triangulation(&vertices);
-    triangles
+pub fn is_in_box<T, P>(point: &P) -> bool where T: BaseFloat, P: Homogenous<T> {
+    let h = point.homogenous_position();
+    h.w > T::zero() && h.x.abs() <= h.w && h.y.abs() <= h.w && h.z.abs() <= h.w
 }
 
 pub fn convex_triangulation<T>(points: &Vec<T>) -> Vec<(T, T, T)> where T: Copy {
@@ -473,6 +504,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_clip_interpolates_varyings() {
+        use shaders::ClipVertex;
+        let clipped = clip(&vec![
+            ClipVertex{position: Vector4{x: 0.0, y: 0.0, z: 0.0, w: 1.0}, varyings: 0.0},
+            ClipVertex{position: Vector4{x: 2.0, y: 0.0, z: 0.0, w: 1.0}, varyings: 4.0},
+        ], &|p: Vector4<f32>| -p.x);
+        assert_eq!(clipped.len(), 3);
+        assert_eq!(clipped[0].position, Vector4{x: 1.0, y: 0.0, z: 0.0, w: 1.0});
+        assert_eq!(clipped[0].varyings, 2.0);
+    }
+
     #[test]
     fn test_no_clip() {
         assert_eq!(
@@ -503,5 +546,19 @@ mod tests {
             vec![],
         );
     }
-}
 
+    #[test]
+    fn test_clip_line() {
+        let (p0, p1) = clip_line(
+            Vector4{x: -3.0, y: 0.0, z: 0.0, w: 1.0},
+            Vector4{x: 0.5, y: 0.5, z: 0.0, w: 1.0},
+        ).unwrap();
+        assert_eq!(p0.x, -1.0);
+        assert!((p0.y - 2.0 / 7.0).abs() < 1e-6);
+        assert_eq!(p1, Vector4{x: 0.5, y: 0.5, z: 0.0, w: 1.0});
+        assert_eq!(clip_line(
+            Vector4{x: 3.0, y: 0.0, z: 0.0, w: 1.0},
+            Vector4{x: 0.0, y: 3.0, z: 0.0, w: 1.0},
+        ), None);
+    }
+}
@@ -0,0 +1,408 @@
+use cgmath::*;
+use colors::*;
+use geometry::*;
+use images::ImageError;
+use materials::*;
+use math::convex_triangulation;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use textures::Texture;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(io::Error),
+    Parse{line: usize, message: String},
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjError::Io(ref error) => write!(f, "i/o error: {}", error),
+            ObjError::Parse{line, ref message} => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ObjError::Io(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ObjError {
+    fn from(error: io::Error) -> Self {
+        ObjError::Io(error)
+    }
+}
+
+/// A run of faces sharing a group name and material.
+#[derive(Clone, Debug)]
+pub struct ObjGroup {
+    pub name: String,
+    pub material: Option<String>,
+    pub mesh: Mesh,
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjModel {
+    pub groups: Vec<ObjGroup>,
+    /// Material libraries named by `mtllib`, as written in the file.
+    pub material_libraries: Vec<String>,
+}
+
+impl ObjModel {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ObjError> {
+        Self::parse(&read_to_string(path)?)
+    }
+
+    /// Loads a model along with every material library it references, which are looked up
+    /// relative to the model's directory.
+    pub fn load_with_materials<P: AsRef<Path>>(path: P) -> Result<(Self, MtlLibrary), ObjError> {
+        let model = Self::load(&path)?;
+        let directory = path.as_ref().parent().unwrap_or(Path::new(""));
+        let mut library = MtlLibrary::new();
+        for name in &model.material_libraries {
+            library.merge(MtlLibrary::load(directory.join(name))?);
+        }
+        Ok((model, library))
+    }
+
+    pub fn parse(source: &str) -> Result<Self, ObjError> {
+        let mut positions: Vec<Vector3<f32>> = vec![];
+        let mut uvs: Vec<Vector2<f32>> = vec![];
+        let mut normals: Vec<Vector3<f32>> = vec![];
+        let mut model = ObjModel{groups: vec![], material_libraries: vec![]};
+        let mut name = String::from("default");
+        let mut material = None;
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: &str| ObjError::Parse{line: line_number, message: message.to_string()};
+            let mut tokens = line.split('#').next().unwrap().split_whitespace();
+            let keyword = match tokens.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let arguments: Vec<&str> = tokens.collect();
+            match keyword {
+                "v" => {
+                    let values = parse_floats(&arguments, 3, 4).ok_or(error("expected 3 or 4 numbers"))?;
+                    positions.push(Vector3{x: values[0], y: values[1], z: values[2]});
+                },
+                "vt" => {
+                    let values = parse_floats(&arguments, 1, 3).ok_or(error("expected 1 to 3 numbers"))?;
+                    // OBJ puts v = 0 at the bottom of the image, textures here put it at the top.
+                    uvs.push(Vector2{x: values[0], y: 1.0 - values.get(1).cloned().unwrap_or(0.0)});
+                },
+                "vn" => {
+                    let values = parse_floats(&arguments, 3, 3).ok_or(error("expected 3 numbers"))?;
+                    normals.push(Vector3{x: values[0], y: values[1], z: values[2]});
+                },
+                "f" => {
+                    if arguments.len() < 3 {
+                        return Err(error("a face needs at least 3 vertices"));
+                    }
+                    let mut face = vec![];
+                    for argument in &arguments {
+                        face.push(parse_face_vertex(argument, &positions, &uvs, &normals)
+                            .map_err(|message| error(&message))?);
+                    }
+                    let group = current_group(&mut model.groups, &name, &material);
+                    for (v0, v1, v2) in convex_triangulation(&face) {
+                        group.mesh.vertices.push(with_face_normals(v0, v1, v2));
+                    }
+                },
+                "g" | "o" => {
+                    name = if arguments.is_empty() {
+                        String::from("default")
+                    } else {
+                        arguments.join(" ")
+                    };
+                },
+                "usemtl" => {
+                    material = Some(arguments.join(" "));
+                },
+                "mtllib" => {
+                    model.material_libraries.extend(arguments.iter().map(|s| s.to_string()));
+                },
+                // Smoothing groups, lines, points and anything more exotic aren't supported.
+                _ => {},
+            }
+        }
+        model.groups.retain(|group| !group.mesh.vertices.is_empty());
+        Ok(model)
+    }
+
+    /// All groups merged into a single mesh.
+    pub fn to_mesh(&self) -> Mesh {
+        Mesh{
+            vertices: self.groups.iter()
+                .flat_map(|group| group.mesh.vertices.iter().cloned())
+                .collect(),
+        }
+    }
+}
+
+fn current_group<'a>(
+    groups: &'a mut Vec<ObjGroup>,
+    name: &String,
+    material: &Option<String>,
+) -> &'a mut ObjGroup {
+    let matches = groups.last()
+        .map(|group| group.name == *name && group.material == *material)
+        .unwrap_or(false);
+    if !matches {
+        groups.push(ObjGroup{name: name.clone(), material: material.clone(), mesh: Mesh{vertices: vec![]}});
+    }
+    groups.last_mut().unwrap()
+}
+
+// Vertices without a normal of their own get the face normal.
+fn with_face_normals(
+    v0: (Vertex3, bool),
+    v1: (Vertex3, bool),
+    v2: (Vertex3, bool),
+) -> (Vertex3, Vertex3, Vertex3) {
+    let normal = (v1.0.position - v0.0.position).cross(v2.0.position - v0.0.position);
+    let face_normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { normal };
+    let fix = |(mut vertex, has_normal): (Vertex3, bool)| {
+        if !has_normal {
+            vertex.normal = face_normal;
+        }
+        vertex
+    };
+    (fix(v0), fix(v1), fix(v2))
+}
+
+// Parses `v`, `v/vt`, `v//vn` or `v/vt/vn`, returning the vertex and whether it had a normal.
+fn parse_face_vertex(
+    argument: &str,
+    positions: &Vec<Vector3<f32>>,
+    uvs: &Vec<Vector2<f32>>,
+    normals: &Vec<Vector3<f32>>,
+) -> Result<(Vertex3, bool), String> {
+    let parts: Vec<&str> = argument.split('/').collect();
+    if parts.len() > 3 {
+        return Err(format!("invalid face vertex '{}'", argument));
+    }
+    let position = lookup(parts[0], positions)?;
+    let uv = match parts.get(1) {
+        Some(part) if !part.is_empty() => lookup(part, uvs)?,
+        _ => Vector2{x: 0.0, y: 0.0},
+    };
+    let normal = match parts.get(2) {
+        Some(part) if !part.is_empty() => Some(lookup(part, normals)?),
+        _ => None,
+    };
+    Ok((
+        Vertex3{
+            position,
+            uv,
+            normal: normal.unwrap_or(Vector3{x: 0.0, y: 0.0, z: 0.0}),
+            attributes: (),
+        },
+        normal.is_some(),
+    ))
+}
+
+// Indices are 1-based; negative indices count back from the most recent element.
+fn lookup<T: Copy>(index: &str, elements: &Vec<T>) -> Result<T, String> {
+    let parsed: i64 = index.parse().map_err(|_| format!("invalid index '{}'", index))?;
+    let resolved = if parsed > 0 {
+        parsed - 1
+    } else {
+        elements.len() as i64 + parsed
+    };
+    if parsed == 0 || resolved < 0 || resolved >= elements.len() as i64 {
+        return Err(format!("index {} out of range", parsed));
+    }
+    Ok(elements[resolved as usize])
+}
+
+fn parse_floats(arguments: &Vec<&str>, min: usize, max: usize) -> Option<Vec<f32>> {
+    if arguments.len() < min || arguments.len() > max {
+        return None;
+    }
+    arguments.iter().map(|argument| argument.parse().ok()).collect()
+}
+
+/// Materials from one or more MTL files. Texture maps are collected into `textures`, and each
+/// material's `texture` is an index into that list, so textures can be registered with the
+/// renderer under the same indices.
+#[derive(Clone, Debug)]
+pub struct MtlLibrary {
+    pub materials: HashMap<String, Material>,
+    pub textures: Vec<PathBuf>,
+}
+
+impl MtlLibrary {
+    pub fn new() -> Self {
+        MtlLibrary{materials: HashMap::new(), textures: vec![]}
+    }
+
+    /// Loads an MTL file; texture paths are resolved relative to its directory.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ObjError> {
+        let directory = path.as_ref().parent().unwrap_or(Path::new("")).to_path_buf();
+        Self::parse(&read_to_string(path)?, &directory)
+    }
+
+    pub fn parse(source: &str, directory: &Path) -> Result<Self, ObjError> {
+        let mut library = Self::new();
+        let mut current: Option<(String, Material)> = None;
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: &str| ObjError::Parse{line: line_number, message: message.to_string()};
+            let mut tokens = line.split('#').next().unwrap().split_whitespace();
+            let keyword = match tokens.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let arguments: Vec<&str> = tokens.collect();
+            if keyword == "newmtl" {
+                if let Some((name, material)) = current.take() {
+                    library.materials.insert(name, material);
+                }
+                current = Some((arguments.join(" "), Material::new()));
+                continue;
+            }
+            let material = match current {
+                Some((_, ref mut material)) => material,
+                None => return Err(error("material statement before newmtl")),
+            };
+            match keyword {
+                "Ka" | "Kd" | "Ks" => {
+                    let values = parse_floats(&arguments, 3, 3).ok_or(error("expected 3 numbers"))?;
+                    let color = FloatColor::from_rgb(values[0], values[1], values[2]);
+                    match keyword {
+                        "Ka" => material.ambient = color,
+                        "Kd" => material.diffuse = color,
+                        _ => material.specular = color,
+                    }
+                },
+                "Ns" => {
+                    let values = parse_floats(&arguments, 1, 1).ok_or(error("expected a number"))?;
+                    material.shininess = values[0];
+                },
+                "d" | "Tr" => {
+                    let values = parse_floats(&arguments, 1, 1).ok_or(error("expected a number"))?;
+                    // Tr is transparency rather than opacity.
+                    material.opacity = if keyword == "d" { values[0] } else { 1.0 - values[0] };
+                },
+                "map_Kd" => {
+                    // Options such as -s or -o come before the file name, which is last.
+                    let file = arguments.last().ok_or(error("expected a file name"))?;
+                    material.texture = Some(library.texture_index(directory.join(file)));
+                },
+                _ => {},
+            }
+        }
+        if let Some((name, material)) = current.take() {
+            library.materials.insert(name, material);
+        }
+        Ok(library)
+    }
+
+    /// Adds another library's materials, renumbering its texture references.
+    pub fn merge(&mut self, other: MtlLibrary) {
+        let MtlLibrary{materials, textures} = other;
+        for (name, mut material) in materials {
+            material.texture = material.texture
+                .map(|index| self.texture_index(textures[index].clone()));
+            self.materials.insert(name, material);
+        }
+    }
+
+    /// Loads every referenced texture, in index order.
+    pub fn load_textures(&self) -> Result<Vec<Texture>, ImageError> {
+        self.textures.iter().map(|path| Texture::load(path)).collect()
+    }
+
+    fn texture_index(&mut self, path: PathBuf) -> usize {
+        match self.textures.iter().position(|texture| *texture == path) {
+            Some(index) => index,
+            None => {
+                self.textures.push(path);
+                self.textures.len() - 1
+            },
+        }
+    }
+}
+
+fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String, ObjError> {
+    let mut source = String::new();
+    File::open(path)?.read_to_string(&mut source)?;
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_obj() {
+        let model = ObjModel::parse("
+            mtllib scene.mtl
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 1
+            vn 0 0 1
+            g floor
+            usemtl tiles
+            f 1/1/1 2/1/1 3/2/1 4/2/1  # a quad
+            usemtl plain
+            f -4 -3 -2
+        ").unwrap();
+        assert_eq!(model.material_libraries, vec!["scene.mtl".to_string()]);
+        assert_eq!(model.groups.len(), 2);
+        assert_eq!(model.groups[0].name, "floor");
+        assert_eq!(model.groups[0].material, Some("tiles".to_string()));
+        assert_eq!(model.groups[0].mesh.vertices.len(), 2);
+        assert_eq!(model.groups[0].mesh.vertices[1].2.position, Vector3{x: 0.0, y: 1.0, z: 0.0});
+        assert_eq!(model.groups[0].mesh.vertices[1].1.uv, Vector2{x: 1.0, y: 0.0});
+        // No normals given, so the face normal is used.
+        let (v0, _, _) = model.groups[1].mesh.vertices[0];
+        assert_eq!(v0.normal, Vector3{x: 0.0, y: 0.0, z: 1.0});
+        assert_eq!(model.to_mesh().vertices.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_obj_bad_index() {
+        match ObjModel::parse("v 0 0 0\nf 1 2 3\n") {
+            Err(ObjError::Parse{line, ..}) => assert_eq!(line, 2),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_parse_mtl() {
+        let library = MtlLibrary::parse("
+            newmtl tiles
+            Kd 0.5 0.25 1
+            Ns 12.5
+            d 0.75
+            map_Kd -s 2 2 1 tiles.png
+            newmtl other
+            Tr 0.25
+            map_Kd tiles.png
+        ", Path::new("assets")).unwrap();
+        let tiles = library.materials["tiles"];
+        assert_eq!((tiles.diffuse.r, tiles.diffuse.g, tiles.diffuse.b), (0.5, 0.25, 1.0));
+        assert_eq!(tiles.shininess, 12.5);
+        assert_eq!(tiles.opacity, 0.75);
+        assert_eq!(library.materials["other"].opacity, 0.75);
+        assert_eq!(tiles.texture, Some(0));
+        assert_eq!(library.materials["other"].texture, Some(0));
+        assert_eq!(library.textures, vec![Path::new("assets").join("tiles.png")]);
+    }
+}
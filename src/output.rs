@@ -0,0 +1,32 @@
+use colors::Color;
+use frame::Frame;
+
+/// Somewhere finished frames go when `Renderer::present` is called: a window, or just memory.
+pub trait OutputTarget {
+    fn present(&mut self, color_buffer: &Frame<Color>);
+}
+
+/// Keeps the most recently presented frame around, for rendering without a display.
+pub struct MemoryTarget {
+    frame: Option<Frame<Color>>,
+}
+
+impl MemoryTarget {
+    pub fn new() -> Self {
+        MemoryTarget{frame: None}
+    }
+
+    pub fn frame(&self) -> Option<&Frame<Color>> {
+        self.frame.as_ref()
+    }
+
+    pub fn take_frame(&mut self) -> Option<Frame<Color>> {
+        self.frame.take()
+    }
+}
+
+impl OutputTarget for MemoryTarget {
+    fn present(&mut self, color_buffer: &Frame<Color>) {
+        self.frame = Some(color_buffer.clone());
+    }
+}
@@ -0,0 +1,142 @@
+use cgmath::*;
+use collision::Ray3;
+use geometry::*;
+use shaders::normal_matrix;
+
+/// Where a ray met a mesh. Distance and position are in world space.
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    /// Index of the hit triangle in `Mesh::vertices`.
+    pub triangle: usize,
+    pub distance: f32,
+    pub position: Vector3<f32>,
+    /// Weights of the triangle's three vertices at the hit point.
+    pub barycentric: (f32, f32, f32),
+    pub uv: Vector2<f32>,
+    /// The interpolated vertex normal in world space.
+    pub normal: Vector3<f32>,
+}
+
+/// Möller–Trumbore intersection of a ray with a triangle, from either side. Returns the
+/// distance along the ray in units of its direction's length, and the weights of `p1` and `p2`.
+pub fn intersect_triangle(
+    ray: &Ray3<f32>,
+    p0: Vector3<f32>,
+    p1: Vector3<f32>,
+    p2: Vector3<f32>,
+) -> Option<(f32, f32, f32)> {
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+    let p = ray.direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let inverse = 1.0 / determinant;
+    let s = ray.origin.to_vec() - p0;
+    let u = s.dot(p) * inverse;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let v = ray.direction.dot(q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(q) * inverse;
+    if t < 0.0 {
+        return None;
+    }
+    Some((t, u, v))
+}
+
+/// The nearest triangle of `mesh`, placed in the world by `object_to_world`, hit by `ray`.
+pub fn ray_cast<A: Copy>(ray: &Ray3<f32>, mesh: &Mesh<A>, object_to_world: Matrix4<f32>) -> Option<RayHit> {
+    let world_to_object = object_to_world.invert()?;
+    // Barycentrics survive affine transforms, so it's cheaper to move the ray than the mesh.
+    let local_ray = ray.transform(world_to_object);
+    let (index, (t, u, v)) = mesh.vertices.iter()
+        .enumerate()
+        .filter_map(|(index, &(v0, v1, v2))| {
+            intersect_triangle(&local_ray, v0.position, v1.position, v2.position).map(|hit| (index, hit))
+        })
+        .min_by(|a, b| (a.1).0.total_cmp(&(b.1).0))?;
+    let (v0, v1, v2) = mesh.vertices[index];
+    let weights = (1.0 - u - v, u, v);
+    let local_position = local_ray.origin.to_vec() + local_ray.direction * t;
+    let position = (object_to_world * local_position.extend(1.0)).truncate();
+    let normal = v0.normal * weights.0 + v1.normal * weights.1 + v2.normal * weights.2;
+    let normal = normal_matrix(object_to_world) * normal;
+    Some(RayHit{
+        triangle: index,
+        distance: (position - ray.origin.to_vec()).magnitude(),
+        position,
+        barycentric: weights,
+        uv: v0.uv * weights.0 + v1.uv * weights.1 + v2.uv * weights.2,
+        normal: if normal.magnitude2() > 0.0 { normal.normalize() } else { normal },
+    })
+}
+
+/// The nearest hit among several meshes, with the index of the mesh that was hit.
+pub fn pick<A: Copy>(ray: &Ray3<f32>, meshes: &[(&Mesh<A>, Matrix4<f32>)]) -> Option<(usize, RayHit)> {
+    meshes.iter()
+        .enumerate()
+        .filter_map(|(index, &(mesh, object_to_world))| ray_cast(ray, mesh, object_to_world).map(|hit| (index, hit)))
+        .min_by(|a, b| a.1.distance.total_cmp(&b.1.distance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camera::*;
+
+    #[test]
+    fn test_unproject_center_pixel() {
+        let camera = Camera::create(
+            70.0,
+            2.0,
+            0.1,
+            100.0,
+            Matrix4::from_translation(Vector3{x: 1.0, y: 2.0, z: 3.0}) * Matrix4::from_angle_y(Deg(90.0)),
+        );
+        let ray = camera.unproject(50.0, 25.0, 100, 50).unwrap();
+        assert!((ray.direction - Vector3{x: -1.0, y: 0.0, z: 0.0}).magnitude() < 1e-4);
+        // The ray starts on the near plane.
+        assert!((ray.origin.to_vec() - Vector3{x: 0.9, y: 2.0, z: 3.0}).magnitude() < 1e-4);
+        let flat = Camera::with_projection(
+            Projection::Orthographic{width: 2.0, height: 2.0, near: 1.0, far: 1.0},
+            Matrix4::identity(),
+        );
+        assert!(flat.unproject(50.0, 25.0, 100, 50).is_none());
+    }
+
+    #[test]
+    fn test_ray_cast_transformed_mesh() {
+        let face = Mesh::xy_face(1.0);
+        let transform = Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -5.0})
+            * Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0);
+        let ray = Ray3::new(Point3::new(0.75, 0.25, 0.0), Vector3{x: 0.0, y: 0.0, z: -1.0});
+        let hit = ray_cast(&ray, &face, transform).unwrap();
+        assert!((hit.distance - 5.0).abs() < 1e-4);
+        assert!((hit.position - Vector3{x: 0.75, y: 0.25, z: -5.0}).magnitude() < 1e-4);
+        assert!((hit.uv - Vector2{x: 0.875, y: 0.75}).magnitude() < 1e-4);
+        assert!((hit.normal - Vector3{x: 0.0, y: 0.0, z: 1.0}).magnitude() < 1e-4);
+        let weights = hit.barycentric;
+        assert!((weights.0 + weights.1 + weights.2 - 1.0).abs() < 1e-5);
+        assert!(ray_cast(&ray, &face, Matrix4::from_translation(Vector3{x: 5.0, y: 0.0, z: -5.0})).is_none());
+    }
+
+    #[test]
+    fn test_pick_nearest_mesh() {
+        let face = Mesh::xy_face(1.0);
+        let near = Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -2.0});
+        let far = Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -4.0});
+        let ray = Ray3::new(Point3::new(0.0, 0.0, 0.0), Vector3{x: 0.0, y: 0.0, z: -1.0});
+        let (index, hit) = pick(&ray, &[(&face, far), (&face, near)]).unwrap();
+        assert_eq!(index, 1);
+        assert!((hit.distance - 2.0).abs() < 1e-4);
+        // Rays pointing away from a mesh miss it.
+        let backwards = Ray3::new(Point3::new(0.0, 0.0, 0.0), Vector3{x: 0.0, y: 0.0, z: 1.0});
+        assert!(pick(&backwards, &[(&face, near)]).is_none());
+    }
+}
@@ -1,135 +1,697 @@
 use frame::Frame;
-use sdl2::{
-    pixels::Color,
-};
+use colors::*;
 use cgmath::*;
 use math::*;
 use num_traits::Float;
-use geometry::*;
 use std;
-use textures::*;
-use colors::*;
-use materials::*;
-use light::*;
+use shaders::*;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use thread_pool::ThreadPool;
+
+// Width and height of the screen tiles that triangles are binned into for parallel shading.
+const TILE_SIZE: i32 = 64;
+
+// Vertices are snapped to 1/256th of a pixel, so that the coverage tests below are exact.
+const SUBPIXEL_BITS: i64 = 8;
+const SUBPIXEL_ONE: i64 = 1 << SUBPIXEL_BITS;
+
+// The most samples a pixel can have; coverage masks hold a bit per sample.
+const MAX_SAMPLES: usize = 8;
+
+// The usual multisample positions, in sixteenths of a pixel from its centre with y pointing
+// down. They're spread so that near-vertical and near-horizontal edges still get even steps.
+const SAMPLES_2: [(i64, i64); 2] = [(4, 4), (-4, -4)];
+const SAMPLES_4: [(i64, i64); 4] = [(-2, -6), (6, -2), (-6, 2), (2, 6)];
+const SAMPLES_8: [(i64, i64); 8] = [(1, -3), (-1, 3), (5, 1), (-3, -5), (-5, 5), (-7, -1), (3, 7), (7, -7)];
 
 pub struct Rasterizer {
+    // Depth and color for every sample, with a pixel's samples next to each other.
     z_buffer: Frame<f32>,
     color_buffer: Frame<Color>,
-    screen_width: u32,
-    screen_height: u32,
+    // The samples averaged down to the screen, when there's more than one per screen pixel.
+    resolved: Option<Frame<Color>>,
+    antialiasing: Antialiasing,
+    // Size of the grid of pixels that's drawn to, which is larger than the screen when
+    // supersampling.
+    width: u32,
+    height: u32,
+    scale: u32,
+    // Where each of a pixel's samples is, in fixed-point units from its centre.
+    sample_offsets: Vec<(i64, i64)>,
+    threads: usize,
+    // The workers for `triangles_parallel`, started the first time they're needed.
+    pool: Option<Arc<ThreadPool>>,
+    state: RenderState,
+}
+
+/// How edges are smoothed. Apart from `None`, these keep several samples for each screen
+/// pixel, and `Rasterizer::resolve` averages them into the final image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Antialiasing {
+    None,
+    /// Draws at `n` times the screen's width and height, shading every subpixel, and averages
+    /// each n by n block. A scale of 0 is taken as 1.
+    Supersample(u32),
+    /// Keeps several depth samples in each pixel, each covered by the triangles over it, but
+    /// only shades the pixel once per triangle.
+    Multisample(Msaa),
+}
+
+/// Samples per pixel for multisampling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Msaa {
+    X2,
+    X4,
+    X8,
+}
+
+impl Msaa {
+    pub fn samples(&self) -> usize {
+        self.offsets().len()
+    }
+
+    fn offsets(&self) -> &'static [(i64, i64)] {
+        match *self {
+            Msaa::X2 => &SAMPLES_2,
+            Msaa::X4 => &SAMPLES_4,
+            Msaa::X8 => &SAMPLES_8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CullMode {
+    None,
+    Front,
+    Back,
+}
+
+/// The order, as seen on screen, in which a front face's vertices go round.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Winding {
+    CounterClockwise,
+    Clockwise,
+}
+
+/// The comparison between a fragment's depth and the depth buffer that lets it be drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DepthFunc {
+    Never,
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+impl DepthFunc {
+    pub fn passes(&self, depth: f32, stored: f32) -> bool {
+        match *self {
+            DepthFunc::Never => false,
+            DepthFunc::Less => depth < stored,
+            DepthFunc::Equal => depth == stored,
+            DepthFunc::LessEqual => depth <= stored,
+            DepthFunc::Greater => depth > stored,
+            DepthFunc::NotEqual => depth != stored,
+            DepthFunc::GreaterEqual => depth >= stored,
+            DepthFunc::Always => true,
+        }
+    }
+}
+
+/// Fixed-function settings for drawing triangles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderState {
+    pub cull_mode: CullMode,
+    pub front_face: Winding,
+    pub depth_func: DepthFunc,
+    /// Whether drawn fragments update the depth buffer.
+    pub depth_write: bool,
+    /// Added to each fragment's NDC depth before the depth test; a small negative bias lets lines
+    /// drawn along a surface win against the surface itself.
+    pub depth_bias: f32,
+    pub blend_mode: BlendMode,
+}
+
+impl RenderState {
+    /// Back faces culled, counter-clockwise front faces, nearest fragment wins, no blending.
+    pub fn new() -> Self {
+        RenderState{
+            cull_mode: CullMode::Back,
+            front_face: Winding::CounterClockwise,
+            depth_func: DepthFunc::Less,
+            depth_write: true,
+            depth_bias: 0.0,
+            blend_mode: BlendMode::Opaque,
+        }
+    }
+}
+
+// A triangle in screen space, ready to be scanned over any part of its bounds.
+struct TriangleSetup<V> {
+    vertices: (ClipVertex<V>, ClipVertex<V>, ClipVertex<V>),
+    ndc: (Vector3<f32>, Vector3<f32>, Vector3<f32>),
+    // Each edge is opposite the vertex with the same index, so its value at a point is
+    // proportional to that vertex's barycentric coordinate.
+    edges: [Edge; 3],
+    // Twice the triangle's area in fixed-point units; the edge values sum to this.
+    area: i64,
+    front_facing: bool,
+    bounds: RectBounds<i32>,
+}
+
+// The edge function of a directed edge in fixed-point screen space: zero on the edge and
+// positive on the side the triangle is on.
+#[derive(Clone, Copy, Debug)]
+struct Edge {
+    a: i64,
+    b: i64,
+    c: i64,
+    // Top-left fill rule: pixel centres exactly on an edge belong to the triangle only if it's a
+    // top or left edge, so that triangles sharing an edge never both draw the pixel.
+    bias: i64,
+}
+
+impl Edge {
+    fn new(from: (i64, i64), to: (i64, i64)) -> Self {
+        let a = from.1 - to.1;
+        let b = to.0 - from.0;
+        let c = -(a * from.0 + b * from.1);
+        // With y pointing down, interior to the right means a left edge, and a horizontal edge
+        // with the interior below is a top edge.
+        let top_left = a > 0 || (a == 0 && b > 0);
+        Edge{a, b, c, bias: if top_left { 0 } else { -1 }}
+    }
+
+    fn at(&self, x: i64, y: i64) -> i64 {
+        self.a * x + self.b * y + self.c
+    }
+}
+
+// A window onto depth and color buffers; `left` and `bottom` are the coordinates of the
+// first pixel, and `stride` the number of pixels per row. Each pixel has a cell per sample.
+struct Target<'a> {
+    z: &'a mut [f32],
+    color: &'a mut [Color],
+    left: i32,
+    bottom: i32,
+    stride: usize,
+    offsets: &'a [(i64, i64)],
+    state: RenderState,
+}
+
+impl <'a> Target<'a> {
+    fn all_samples(&self) -> u32 {
+        (1 << self.offsets.len()) - 1
+    }
 }
 
 impl Rasterizer {
+    /// A rasterizer without antialiasing; `with_antialiasing` picks a kind.
     pub fn create(screen_width: u32, screen_height: u32) -> Self {
+        Self::with_antialiasing(screen_width, screen_height, Antialiasing::None)
+    }
+
+    pub fn with_antialiasing(screen_width: u32, screen_height: u32, antialiasing: Antialiasing) -> Self {
+        let centre: &[(i64, i64)] = &[(0, 0)];
+        let (scale, offsets) = match antialiasing {
+            Antialiasing::None => (1, centre),
+            Antialiasing::Supersample(n) => (std::cmp::max(n, 1), centre),
+            Antialiasing::Multisample(msaa) => (1, msaa.offsets()),
+        };
+        let sample_offsets: Vec<(i64, i64)> = offsets.iter()
+            .map(|&(x, y)| (x * SUBPIXEL_ONE / 16, y * SUBPIXEL_ONE / 16))
+            .collect();
+        let (width, height) = (screen_width * scale, screen_height * scale);
+
         let z_buffer = Frame::new(
-            screen_width as usize,
-            screen_height as usize,
+            width as usize * sample_offsets.len(),
+            height as usize,
             Float::max_value()
         );
 
         let color_buffer = Frame::new(
-            screen_width as usize,
-            screen_height as usize,
+            width as usize * sample_offsets.len(),
+            height as usize,
             Color::RGB(0, 0, 0),
         );
 
+        let resolved = if scale > 1 || sample_offsets.len() > 1 {
+            Some(Frame::new(screen_width as usize, screen_height as usize, Color::RGB(0, 0, 0)))
+        } else {
+            None
+        };
+
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
         return Rasterizer {
             z_buffer,
             color_buffer,
-            screen_width,
-            screen_height,
+            resolved,
+            antialiasing,
+            width,
+            height,
+            scale,
+            sample_offsets,
+            threads,
+            pool: None,
+            state: RenderState::new(),
         };
     }
 
-    // TODO: consolidate most of these fields...
-    pub fn triangle(
+    pub fn antialiasing(&self) -> Antialiasing {
+        return self.antialiasing;
+    }
+
+    /// Sets how many threads `triangles_parallel` shades tiles on; 1 keeps everything on the
+    /// calling thread. Defaults to the number of available cores. The threads are started
+    /// once, on the first parallel draw, and kept until the rasterizer is dropped or this is
+    /// changed.
+    pub fn set_threads(&mut self, threads: usize) {
+        let threads = std::cmp::max(threads, 1);
+        if threads != self.threads {
+            self.threads = threads;
+            self.pool = None;
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        return self.threads;
+    }
+
+    pub(crate) fn pool(&mut self) -> Arc<ThreadPool> {
+        let threads = self.threads;
+        self.pool.get_or_insert_with(|| Arc::new(ThreadPool::new(threads))).clone()
+    }
+
+    // Has this rasterizer's parallel draws use the threads of another's, rather than starting
+    // its own.
+    pub(crate) fn share_pool(&mut self, pool: Arc<ThreadPool>) {
+        self.threads = pool.threads();
+        self.pool = Some(pool);
+    }
+
+    /// Sets the culling, depth and blending used by subsequent draws.
+    pub fn set_render_state(&mut self, state: RenderState) {
+        self.state = state;
+    }
+
+    pub fn render_state(&self) -> RenderState {
+        return self.state;
+    }
+
+    /// Sets how shaded fragments are combined with the color buffer.
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.state.blend_mode = blend_mode;
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        return self.state.blend_mode;
+    }
+
+    /// Sets whether drawn fragments update the depth buffer. They're depth tested either way,
+    /// so turning this off lets blended surfaces be drawn behind one another in any order.
+    pub fn set_depth_write(&mut self, depth_write: bool) {
+        self.state.depth_write = depth_write;
+    }
+
+    pub fn depth_write(&self) -> bool {
+        return self.state.depth_write;
+    }
+
+    /// Rasterizes a triangle whose vertices have already been clipped to the view volume,
+    /// handing each covered pixel that passes the depth test to the fragment shader with its
+    /// varyings perspective-correctly interpolated.
+    pub fn triangle<V, F>(
+        &mut self,
+        vertices: (ClipVertex<V>, ClipVertex<V>, ClipVertex<V>),
+        fragment_shader: &F,
+    ) where V: Varying, F: FragmentShader<V> + ?Sized {
+        if let Some(setup) = self.setup(vertices) {
+            let mut target = self.target();
+            rasterize(&setup, setup.bounds, &mut target, fragment_shader);
+        }
+    }
+
+    /// Rasterizes a batch of clipped triangles in order, on the calling thread.
+    pub fn triangles<V, F>(
         &mut self,
-        world_vertices: (Vertex4, Vertex4, Vertex4),
-        camera_vertices: (Vertex4, Vertex4, Vertex4),
-        clip_vertices: (Vertex4, Vertex4, Vertex4),
-        fragment_processor: &Fn(
-            &Vector4<f32>,
-            &Vector4<f32>,
-            &Vector2<f32>,
-        ) -> FloatColor,
-    ) {
-        // Return if triangle is facing away from camera.
-        let edge1 = (clip_vertices.1.position - clip_vertices.0.position).truncate();
-        let edge2 = (clip_vertices.2.position - clip_vertices.0.position).truncate();
-        if edge1.cross(edge2).dot(Vector3{x: 0.0, y: 0.0, z: 1.0}) < 0.0 {
+        triangles: &[(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)],
+        fragment_shader: &F,
+    ) where V: Varying, F: FragmentShader<V> + ?Sized {
+        for vertices in triangles {
+            self.triangle(*vertices, fragment_shader);
+        }
+    }
+
+    /// Like `triangles`, but for shaders that can be shared between threads. The screen is split
+    /// into tiles, each tile is handed the triangles overlapping it, and tiles are shaded in
+    /// parallel. Every tile draws its triangles in batch order, so the result is identical to
+    /// calling `triangle` for each one in turn.
+    pub fn triangles_parallel<V, F>(
+        &mut self,
+        triangles: &[(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)],
+        fragment_shader: &F,
+    ) where V: Varying + Sync, F: FragmentShader<V> + Sync + ?Sized {
+        let setups: Vec<TriangleSetup<V>> = triangles.iter()
+            .filter_map(|vertices| self.setup(*vertices))
+            .collect();
+        if self.threads <= 1 || setups.len() <= 1 {
+            let mut target = self.target();
+            for setup in &setups {
+                rasterize(setup, setup.bounds, &mut target, fragment_shader);
+            }
             return;
         }
 
-        // Compute coordinates of triangle in screen space.
-        let projected_triangle = Triangle{
-            p0: Point2{
-                x: ((clip_vertices.0.position.x + 1.0) / 2.0) * self.color_buffer.width() as f32,
-                y: ((1.0 - clip_vertices.0.position.y) / 2.0) * self.color_buffer.height() as f32,
-            },
-            p1: Point2{
-                x: ((clip_vertices.1.position.x + 1.0) / 2.0) * self.color_buffer.width() as f32,
-                y: ((1.0 - clip_vertices.1.position.y) / 2.0) * self.color_buffer.height() as f32,
-            },
-            p2: Point2{
-                x: ((clip_vertices.2.position.x + 1.0) / 2.0) * self.color_buffer.width() as f32,
-                y: ((1.0 - clip_vertices.2.position.y) / 2.0) * self.color_buffer.height() as f32,
-            },
+        let tiles_x = (self.width as i32 + TILE_SIZE - 1) / TILE_SIZE;
+        let tiles_y = (self.height as i32 + TILE_SIZE - 1) / TILE_SIZE;
+        let mut bins: Vec<Vec<usize>> = vec![vec![]; (tiles_x * tiles_y) as usize];
+        for (index, setup) in setups.iter().enumerate() {
+            for tile_y in setup.bounds.bottom / TILE_SIZE..setup.bounds.top / TILE_SIZE + 1 {
+                for tile_x in setup.bounds.left / TILE_SIZE..setup.bounds.right / TILE_SIZE + 1 {
+                    bins[(tile_y * tiles_x + tile_x) as usize].push(index);
+                }
+            }
+        }
+
+        let screen = RectBounds{
+            left: 0,
+            bottom: 0,
+            right: self.width as i32 - 1,
+            top: self.height as i32 - 1,
+        };
+        let tile_bounds = |tile: usize| {
+            let left = (tile as i32 % tiles_x) * TILE_SIZE;
+            let bottom = (tile as i32 / tiles_x) * TILE_SIZE;
+            RectBounds{left, bottom, right: left + TILE_SIZE - 1, top: bottom + TILE_SIZE - 1}
+                .overlap(screen)
+                .unwrap()
+        };
+
+        // Workers take tiles off a shared counter, render them into buffers of their own, and
+        // hand them back to be copied into the frame.
+        let next_tile = AtomicUsize::new(0);
+        let state = self.state;
+        let samples = self.sample_offsets.len();
+        let rendered = Mutex::new(vec![]);
+        let pool = self.pool();
+        {
+            let z_buffer = &self.z_buffer;
+            let color_buffer = &self.color_buffer;
+            let offsets = &self.sample_offsets[..];
+            pool.run(&|_| {
+                let mut done = vec![];
+                loop {
+                    let tile = next_tile.fetch_add(1, Ordering::Relaxed);
+                    if tile >= bins.len() {
+                        break;
+                    }
+                    if bins[tile].is_empty() {
+                        continue;
+                    }
+                    let bounds = tile_bounds(tile);
+                    let (mut z, mut color) = copy_region(z_buffer, color_buffer, bounds, samples);
+                    {
+                        let mut target = Target{
+                            z: &mut z,
+                            color: &mut color,
+                            left: bounds.left,
+                            bottom: bounds.bottom,
+                            stride: (bounds.right - bounds.left + 1) as usize,
+                            offsets,
+                            state,
+                        };
+                        for &index in &bins[tile] {
+                            let setup = &setups[index];
+                            if let Some(overlap) = setup.bounds.overlap(bounds) {
+                                rasterize(setup, overlap, &mut target, fragment_shader);
+                            }
+                        }
+                    }
+                    done.push((tile, z, color));
+                }
+                rendered.lock().unwrap().extend(done);
+            });
+        }
+
+        for (tile, z, color) in rendered.into_inner().unwrap() {
+            let bounds = tile_bounds(tile);
+            let width = (bounds.right - bounds.left + 1) as usize * samples;
+            for y in bounds.bottom..bounds.top + 1 {
+                let row = (y - bounds.bottom) as usize * width;
+                let start = y as usize * self.color_buffer.width() + bounds.left as usize * samples;
+                self.z_buffer.cells[start..start + width].copy_from_slice(&z[row..row + width]);
+                self.color_buffer.cells[start..start + width].copy_from_slice(&color[row..row + width]);
+            }
+        }
+    }
+
+    /// Draws a one pixel wide line between two vertices that have already been clipped to the
+    /// view volume. A pixel is drawn wherever the line crosses the centre line of its column
+    /// (or row, for steep lines), except at the far end, so lines joined end to end don't
+    /// both draw the pixel they share. When supersampling, the line is as many subpixels thick
+    /// as there are in a screen pixel.
+    pub fn line<V, F>(&mut self, vertices: (ClipVertex<V>, ClipVertex<V>), fragment_shader: &F)
+        where V: Varying, F: FragmentShader<V> + ?Sized {
+        let (v0, v1) = vertices;
+        let (ndc0, ndc1) = (from_homogenous(v0.position), from_homogenous(v1.position));
+        let (p0, p1) = (self.to_screen(ndc0), self.to_screen(ndc1));
+        let x_major = (p1.x - p0.x).abs() >= (p1.y - p0.y).abs();
+        let (start, end) = if x_major { (p0.x, p1.x) } else { (p0.y, p1.y) };
+        if start == end {
+            return;
+        }
+        let varyings_at = |t: f32| {
+            let weights = ((1.0 - t) / v0.position.w, t / v1.position.w);
+            let inv_w = weights.0 + weights.1;
+            v0.varyings.scaled(weights.0 / inv_w).added(&v1.varyings.scaled(weights.1 / inv_w))
+        };
+        let (width, height) = (self.width as i32, self.height as i32);
+        let thickness = self.scale as i32;
+        let mut target = self.target();
+        // Centres from the start inclusive to the end exclusive.
+        let (first, last) = if end > start {
+            ((start - 0.5).ceil() as i32, (end - 0.5).ceil() as i32 - 1)
+        } else {
+            ((end - 0.5).floor() as i32 + 1, (start - 0.5).floor() as i32)
         };
-        let optional_bounds =
-            RectBounds::<i32>::from(RectBounds::bounds_of_triangle(projected_triangle))
-                .overlap(RectBounds{
-                    left: 0,
-                    bottom: 0,
-                    right: (self.screen_width - 1) as i32,
-                    top: (self.screen_height - 1) as i32,
+        for major in first..last + 1 {
+            let t = (major as f32 + 0.5 - start) / (end - start);
+            let minor = if x_major { p0.y + t * (p1.y - p0.y) } else { p0.x + t * (p1.x - p0.x) };
+            let z = ndc0.z + t * (ndc1.z - ndc0.z);
+            for across in 0..thickness {
+                let minor = (minor + across as f32 - (thickness - 1) as f32 / 2.0).floor() as i32;
+                let (x, y) = if x_major { (major, minor) } else { (minor, major) };
+                if x < 0 || y < 0 || x >= width || y >= height {
+                    continue;
+                }
+                let covered = target.all_samples();
+                write_fragment(&mut target, x, y, covered, &[z; MAX_SAMPLES], 1.0, fragment_shader, || {
+                    let varyings = varyings_at(t);
+                    // Varyings only change along the line's major axis.
+                    let step = varyings_at(t + 1.0 / (end - start).abs()).added(&varyings.scaled(-1.0));
+                    let (ddx, ddy) = if x_major { (step, step.scaled(0.0)) } else { (step.scaled(0.0), step) };
+                    Fragment{x: x as usize, y: y as usize, depth: z, varyings, ddx, ddy, front_facing: true}
+                });
+            }
+        }
+    }
+
+    /// Draws a vertex that lies inside the view volume as the single pixel containing it, or
+    /// when supersampling, the block of subpixels the size of a screen pixel around it.
+    pub fn point<V, F>(&mut self, vertex: ClipVertex<V>, fragment_shader: &F)
+        where V: Varying, F: FragmentShader<V> + ?Sized {
+        let ndc = from_homogenous(vertex.position);
+        let screen = self.to_screen(ndc);
+        let size = self.scale as i32;
+        let left = (screen.x - (size - 1) as f32 / 2.0).floor() as i32;
+        let bottom = (screen.y - (size - 1) as f32 / 2.0).floor() as i32;
+        let (width, height) = (self.width as i32, self.height as i32);
+        let mut target = self.target();
+        let covered = target.all_samples();
+        for y in std::cmp::max(bottom, 0)..std::cmp::min(bottom + size, height) {
+            for x in std::cmp::max(left, 0)..std::cmp::min(left + size, width) {
+                write_fragment(&mut target, x, y, covered, &[ndc.z; MAX_SAMPLES], 1.0, fragment_shader, || Fragment{
+                    x: x as usize,
+                    y: y as usize,
+                    depth: ndc.z,
+                    varyings: vertex.varyings,
+                    ddx: vertex.varyings.scaled(0.0),
+                    ddy: vertex.varyings.scaled(0.0),
+                    front_facing: true,
                 });
-        // ugh because I don't want to match and indent
-        let bounds = optional_bounds.unwrap_or(RectBounds{top: 0, bottom: 0, left: 0, right: 0});
+            }
+        }
+    }
+
+    /// Draws an anti-aliased line `width` screen pixels wide, with round ends, between two
+    /// vertices that have already been clipped to the view volume. Each pixel's coverage comes from the
+    /// distance between its centre and the line.
+    pub fn smooth_line<V, F>(&mut self, vertices: (ClipVertex<V>, ClipVertex<V>), width: f32, fragment_shader: &F)
+        where V: Varying, F: FragmentShader<V> + ?Sized {
+        let (v0, v1) = vertices;
+        let (ndc0, ndc1) = (from_homogenous(v0.position), from_homogenous(v1.position));
+        let (p0, p1) = (self.to_screen(ndc0), self.to_screen(ndc1));
+        let direction = p1 - p0;
+        let length2 = direction.magnitude2();
+        let radius = width * self.scale as f32 / 2.0;
+        let varyings_at = |t: f32| {
+            let weights = ((1.0 - t) / v0.position.w, t / v1.position.w);
+            let inv_w = weights.0 + weights.1;
+            v0.varyings.scaled(weights.0 / inv_w).added(&v1.varyings.scaled(weights.1 / inv_w))
+        };
+        // How far along the line a one pixel step in x or y moves the closest point.
+        let (dt_dx, dt_dy) = if length2 > 0.0 { (direction.x / length2, direction.y / length2) } else { (0.0, 0.0) };
+        let bounds = self.pixels_within(
+            Point2{x: p0.x.min(p1.x), y: p0.y.min(p1.y)},
+            Point2{x: p0.x.max(p1.x), y: p0.y.max(p1.y)},
+            radius + 0.5,
+        );
+        let bounds = match bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let mut target = self.target();
         for y in bounds.bottom..bounds.top + 1 {
-            let optional_bounds = projected_triangle.bounds_at_height(y as f32);
-            match optional_bounds {
-                Some(line_bounds) => {
-                    let x_start = std::cmp::max(line_bounds.0 as i32, 0);
-                    let x_end = std::cmp::min(line_bounds.1 as i32, (self.screen_width - 1) as i32);
-                    for x in x_start..x_end + 1 {
-                        let point = Point2{x: x as f32, y: y as f32};
-                        let bary = projected_triangle.barycentric_coordinates(point);
-                        // should maybe be -w?
-                        let adjusted_bary = (
-                            bary.0 / -camera_vertices.0.position.z,
-                            bary.1 / -camera_vertices.1.position.z,
-                            bary.2 / -camera_vertices.2.position.z,
-                        );
-                        let inv_z = adjusted_bary.0 + adjusted_bary.1 + adjusted_bary.2;
-                        let z = 1.0 / inv_z;
-                        if z <= 0.0 {
-                            continue;
-                        }
-                        if z < self.z_buffer.at(x as usize, y as usize).unwrap() {
-                            let w0 = z * adjusted_bary.0;
-                            let w1 = z * adjusted_bary.1;
-                            let w2 = z * adjusted_bary.2;
-                            let normal = (world_vertices.0.normal * w0)
-                                + (world_vertices.1.normal * w1)
-                                + (world_vertices.2.normal * w2);
-                            let uvs = clip_vertices.0.uv * w0
-                                + clip_vertices.1.uv * w1
-                                + clip_vertices.2.uv * w2;
-                            let world = (world_vertices.0.position * w0)
-                                + (world_vertices.1.position * w1)
-                                + (world_vertices.2.position * w2);
-                            let color = fragment_processor(
-                                &world,
-                                &normal,
-                                &uvs,
-                            );
-                            self.color_buffer.set(x as usize, y as usize, color.as_sdl_color());
-                            self.z_buffer.set(x as usize, y as usize, z);
-                        }
+            for x in bounds.left..bounds.right + 1 {
+                let centre = Point2{x: x as f32 + 0.5, y: y as f32 + 0.5};
+                let t = if length2 > 0.0 { ((centre - p0).dot(direction) / length2).max(0.0).min(1.0) } else { 0.0 };
+                let distance = (centre - (p0 + direction * t)).magnitude();
+                let coverage = (radius + 0.5 - distance).min(1.0);
+                if coverage <= 0.0 {
+                    continue;
+                }
+                let z = ndc0.z + t * (ndc1.z - ndc0.z);
+                let covered = target.all_samples();
+                write_fragment(&mut target, x, y, covered, &[z; MAX_SAMPLES], coverage, fragment_shader, || {
+                    let varyings = varyings_at(t);
+                    let negated = varyings.scaled(-1.0);
+                    Fragment{
+                        x: x as usize,
+                        y: y as usize,
+                        depth: z,
+                        varyings,
+                        ddx: varyings_at(t + dt_dx).added(&negated),
+                        ddy: varyings_at(t + dt_dy).added(&negated),
+                        front_facing: true,
                     }
-                },
-                None => {},
-            };
+                });
+            }
+        }
+    }
+
+    /// Draws an anti-aliased round point `size` screen pixels across, centred on a vertex that lies
+    /// inside the view volume.
+    pub fn smooth_point<V, F>(&mut self, vertex: ClipVertex<V>, size: f32, fragment_shader: &F)
+        where V: Varying, F: FragmentShader<V> + ?Sized {
+        self.smooth_line((vertex, vertex), size, fragment_shader);
+    }
+
+    // The pixels whose centres are within `margin` of the box from `min` to `max`, clamped to the
+    // screen, or None if that's empty.
+    fn pixels_within(&self, min: Point2<f32>, max: Point2<f32>, margin: f32) -> Option<RectBounds<i32>> {
+        let left = std::cmp::max((min.x - margin - 0.5).ceil() as i32, 0);
+        let bottom = std::cmp::max((min.y - margin - 0.5).ceil() as i32, 0);
+        let right = std::cmp::min((max.x + margin - 0.5).floor() as i32, self.width as i32 - 1);
+        let top = std::cmp::min((max.y + margin - 0.5).floor() as i32, self.height as i32 - 1);
+        if left > right || bottom > top {
+            return None;
+        }
+        Some(RectBounds{left, bottom, right, top})
+    }
+
+    // Projects a clipped triangle to the screen, or returns None if it is culled, degenerate,
+    // or covers no pixel centres on screen.
+    fn setup<V: Varying>(
+        &self,
+        vertices: (ClipVertex<V>, ClipVertex<V>, ClipVertex<V>),
+    ) -> Option<TriangleSetup<V>> {
+        let (a, b, c) = vertices;
+        let (ndc_a, ndc_b, ndc_c) = (from_homogenous(a.position), from_homogenous(b.position), from_homogenous(c.position));
+        // Winding is judged in NDC, where y points up as it does on screen.
+        let counter_clockwise = (ndc_b - ndc_a).cross(ndc_c - ndc_a).z > 0.0;
+        let front_facing = self.facing(counter_clockwise)?;
+
+        // The edge functions expect vertices counter-clockwise in pixel coordinates, where y
+        // points down, so triangles counter-clockwise in NDC get two vertices swapped.
+        let (v0, v1, v2) = if counter_clockwise { (a, c, b) } else { (a, b, c) };
+        let (ndc0, ndc1, ndc2) = if counter_clockwise { (ndc_a, ndc_c, ndc_b) } else { (ndc_a, ndc_b, ndc_c) };
+
+        let snap = |ndc: Vector3<f32>| {
+            let point = self.to_screen(ndc);
+            (
+                (point.x * SUBPIXEL_ONE as f32).round() as i64,
+                (point.y * SUBPIXEL_ONE as f32).round() as i64,
+            )
+        };
+        let (p0, p1, p2) = (snap(ndc0), snap(ndc1), snap(ndc2));
+        let area = Edge::new(p0, p1).at(p2.0, p2.1);
+        if area <= 0 {
+            // Snapping can collapse a sliver or flip its winding.
+            return None;
+        }
+        let edges = [Edge::new(p1, p2), Edge::new(p2, p0), Edge::new(p0, p1)];
+
+        // Pixel x has its centre at x + 0.5, and its samples around that.
+        let min_x = std::cmp::min(p0.0, std::cmp::min(p1.0, p2.0));
+        let max_x = std::cmp::max(p0.0, std::cmp::max(p1.0, p2.0));
+        let min_y = std::cmp::min(p0.1, std::cmp::min(p1.1, p2.1));
+        let max_y = std::cmp::max(p0.1, std::cmp::max(p1.1, p2.1));
+        let spread = self.sample_offsets.iter().map(|&(x, y)| std::cmp::max(x.abs(), y.abs())).max().unwrap_or(0);
+        let first_pixel = |min: i64| (min - spread - SUBPIXEL_ONE / 2 + SUBPIXEL_ONE - 1).div_euclid(SUBPIXEL_ONE);
+        let last_pixel = |max: i64| (max + spread - SUBPIXEL_ONE / 2).div_euclid(SUBPIXEL_ONE);
+        let left = std::cmp::max(first_pixel(min_x), 0);
+        let bottom = std::cmp::max(first_pixel(min_y), 0);
+        let right = std::cmp::min(last_pixel(max_x), self.width as i64 - 1);
+        let top = std::cmp::min(last_pixel(max_y), self.height as i64 - 1);
+        if left > right || bottom > top {
+            return None;
+        }
+        let bounds = RectBounds{left: left as i32, right: right as i32, bottom: bottom as i32, top: top as i32};
+        Some(TriangleSetup{vertices: (v0, v1, v2), ndc: (ndc0, ndc1, ndc2), edges, area, front_facing, bounds})
+    }
+
+    /// Whether the current render state culls a triangle that has already been clipped.
+    pub fn culls<V>(&self, vertices: &(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)) -> bool {
+        let ndc0 = from_homogenous(vertices.0.position);
+        let ndc1 = from_homogenous(vertices.1.position);
+        let ndc2 = from_homogenous(vertices.2.position);
+        self.facing((ndc1 - ndc0).cross(ndc2 - ndc0).z > 0.0).is_none()
+    }
+
+    // Whether a triangle with the given winding in NDC is front facing, or None if it's culled.
+    fn facing(&self, counter_clockwise: bool) -> Option<bool> {
+        let front_facing = counter_clockwise == (self.state.front_face == Winding::CounterClockwise);
+        match self.state.cull_mode {
+            CullMode::Front if front_facing => None,
+            CullMode::Back if !front_facing => None,
+            _ => Some(front_facing),
+        }
+    }
+
+    fn to_screen(&self, ndc: Vector3<f32>) -> Point2<f32> {
+        Point2{
+            x: ((ndc.x + 1.0) / 2.0) * self.width as f32,
+            y: ((1.0 - ndc.y) / 2.0) * self.height as f32,
+        }
+    }
+
+    // The whole of the depth and color buffers.
+    fn target<'a>(&'a mut self) -> Target<'a> {
+        Target{
+            z: &mut self.z_buffer.cells,
+            color: &mut self.color_buffer.cells,
+            left: 0,
+            bottom: 0,
+            stride: self.width as usize,
+            offsets: &self.sample_offsets,
+            state: self.state,
         }
     }
 
@@ -138,11 +700,485 @@ impl Rasterizer {
         self.z_buffer.set_all(Float::max_value());
     }
 
+    /// Averages the samples under each screen pixel into the image returned by
+    /// `get_color_buffer`. Without antialiasing there's nothing to do.
+    pub fn resolve(&mut self) {
+        let resolved = match self.resolved {
+            Some(ref mut resolved) => resolved,
+            None => return,
+        };
+        let scale = self.scale as usize;
+        // Samples in one row of a screen pixel's block.
+        let span = scale * self.sample_offsets.len();
+        let count = (span * scale) as u32;
+        let row = self.color_buffer.width();
+        for y in 0..resolved.height() {
+            for x in 0..resolved.width() {
+                let mut sum = [0u32; 4];
+                for sub_y in 0..scale {
+                    let start = (y * scale + sub_y) * row + x * span;
+                    for color in &self.color_buffer.cells[start..start + span] {
+                        sum[0] += color.r as u32;
+                        sum[1] += color.g as u32;
+                        sum[2] += color.b as u32;
+                        sum[3] += color.a as u32;
+                    }
+                }
+                let average = |total: u32| ((total + count / 2) / count) as u8;
+                resolved.set(x, y, Color::RGBA(average(sum[0]), average(sum[1]), average(sum[2]), average(sum[3])));
+            }
+        }
+    }
+
+    /// The rendered image at screen size. With antialiasing it's only brought up to date by
+    /// `resolve`.
     pub fn get_color_buffer(&self) -> &Frame<Color> {
-        return &self.color_buffer;
+        return self.resolved.as_ref().unwrap_or(&self.color_buffer);
+    }
+
+    /// The depth of every sample; with antialiasing there are several for each screen pixel.
+    pub fn get_z_buffer(&self) -> &Frame<f32> {
+        return &self.z_buffer;
     }
 }
 
+fn copy_region(
+    z_buffer: &Frame<f32>,
+    color_buffer: &Frame<Color>,
+    bounds: RectBounds<i32>,
+    samples: usize,
+) -> (Vec<f32>, Vec<Color>) {
+    let width = (bounds.right - bounds.left + 1) as usize * samples;
+    let mut z = Vec::with_capacity(width * (bounds.top - bounds.bottom + 1) as usize);
+    let mut color = Vec::with_capacity(z.capacity());
+    for y in bounds.bottom..bounds.top + 1 {
+        let start = y as usize * z_buffer.width() + bounds.left as usize * samples;
+        z.extend_from_slice(&z_buffer.cells[start..start + width]);
+        color.extend_from_slice(&color_buffer.cells[start..start + width]);
+    }
+    (z, color)
+}
 
+// Scans the part of a triangle within `bounds`, which must lie inside the target.
+fn rasterize<V, F>(
+    setup: &TriangleSetup<V>,
+    bounds: RectBounds<i32>,
+    target: &mut Target,
+    fragment_shader: &F,
+) where V: Varying, F: FragmentShader<V> + ?Sized {
+    let (v0, v1, v2) = setup.vertices;
+    let (ndc0, ndc1, ndc2) = setup.ndc;
+    let edges = &setup.edges;
+    let area = setup.area as f32;
+    // Depth is affine in screen space, but varyings are affine in clip space, so they need
+    // weighting by 1/w.
+    let varyings_at = |bary: (f32, f32, f32)| {
+        let adjusted_bary = (
+            bary.0 / v0.position.w,
+            bary.1 / v1.position.w,
+            bary.2 / v2.position.w,
+        );
+        let inv_w = adjusted_bary.0 + adjusted_bary.1 + adjusted_bary.2;
+        V::weighted_sum(
+            (&v0.varyings, &v1.varyings, &v2.varyings),
+            (
+                adjusted_bary.0 / inv_w,
+                adjusted_bary.1 / inv_w,
+                adjusted_bary.2 / inv_w,
+            ),
+        )
+    };
+    // Edge values change by a constant for each pixel step, and so do barycentric coordinates.
+    let step_x = [edges[0].a * SUBPIXEL_ONE, edges[1].a * SUBPIXEL_ONE, edges[2].a * SUBPIXEL_ONE];
+    let step_y = [edges[0].b * SUBPIXEL_ONE, edges[1].b * SUBPIXEL_ONE, edges[2].b * SUBPIXEL_ONE];
+    let bary_step_x = (step_x[0] as f32 / area, step_x[1] as f32 / area, step_x[2] as f32 / area);
+    let bary_step_y = (step_y[0] as f32 / area, step_y[1] as f32 / area, step_y[2] as f32 / area);
+    // How far each sample's edge values are from those at the pixel centre.
+    let sample_steps: Vec<[i64; 3]> = target.offsets.iter()
+        .map(|&(x, y)| [edges[0].a * x + edges[0].b * y, edges[1].a * x + edges[1].b * y, edges[2].a * x + edges[2].b * y])
+        .collect();
 
+    let start_x = bounds.left as i64 * SUBPIXEL_ONE + SUBPIXEL_ONE / 2;
+    let start_y = bounds.bottom as i64 * SUBPIXEL_ONE + SUBPIXEL_ONE / 2;
+    let mut row = [
+        edges[0].at(start_x, start_y),
+        edges[1].at(start_x, start_y),
+        edges[2].at(start_x, start_y),
+    ];
+    for y in bounds.bottom..bounds.top + 1 {
+        let mut w = row;
+        for x in bounds.left..bounds.right + 1 {
+            let mut covered = 0;
+            let mut first_covered = None;
+            let mut depths = [0.0; MAX_SAMPLES];
+            for (sample, step) in sample_steps.iter().enumerate() {
+                let ws = [w[0] + step[0], w[1] + step[1], w[2] + step[2]];
+                if ws[0] + edges[0].bias >= 0 && ws[1] + edges[1].bias >= 0 && ws[2] + edges[2].bias >= 0 {
+                    let bary = (ws[0] as f32 / area, ws[1] as f32 / area, ws[2] as f32 / area);
+                    covered |= 1 << sample;
+                    first_covered = first_covered.or(Some(ws));
+                    depths[sample] = bary.0 * ndc0.z + bary.1 * ndc1.z + bary.2 * ndc2.z;
+                }
+            }
+            if let Some(first_covered) = first_covered {
+                // Shading happens once, at the pixel centre if it's on the triangle, or else at
+                // the first covered sample, so varyings aren't extrapolated past the vertices.
+                let centre_covered = w[0] + edges[0].bias >= 0 && w[1] + edges[1].bias >= 0 && w[2] + edges[2].bias >= 0;
+                let at = if centre_covered { w } else { first_covered };
+                let bary = (at[0] as f32 / area, at[1] as f32 / area, at[2] as f32 / area);
+                let z = bary.0 * ndc0.z + bary.1 * ndc1.z + bary.2 * ndc2.z;
+                write_fragment(target, x, y, covered, &depths, 1.0, fragment_shader, || {
+                    let varyings = varyings_at(bary);
+                    // Derivatives come from extending the interpolation one pixel over, which is
+                    // exact for the plane the triangle lies in.
+                    let negated = varyings.scaled(-1.0);
+                    let ddx = varyings_at((
+                        bary.0 + bary_step_x.0,
+                        bary.1 + bary_step_x.1,
+                        bary.2 + bary_step_x.2,
+                    )).added(&negated);
+                    let ddy = varyings_at((
+                        bary.0 + bary_step_y.0,
+                        bary.1 + bary_step_y.1,
+                        bary.2 + bary_step_y.2,
+                    )).added(&negated);
+                    Fragment{
+                        x: x as usize,
+                        y: y as usize,
+                        depth: z,
+                        varyings,
+                        ddx,
+                        ddy,
+                        front_facing: setup.front_facing,
+                    }
+                });
+            }
+            for i in 0..3 {
+                w[i] += step_x[i];
+            }
+        }
+        for i in 0..3 {
+            row[i] += step_y[i];
+        }
+    }
+}
 
+// Depth tests the samples of the pixel at (x, y) that are set in `covered`, each at its own
+// depth, then shades the pixel once and blends it into the samples that passed. The pixel must
+// lie inside the target, and the fragment is only built once it's known to be needed.
+// Partially covered pixels of anti-aliased primitives have their alpha scaled by `coverage`,
+// and are alpha blended even when drawing opaquely.
+fn write_fragment<V, F, B>(
+    target: &mut Target,
+    x: i32,
+    y: i32,
+    covered: u32,
+    depths: &[f32; MAX_SAMPLES],
+    coverage: f32,
+    fragment_shader: &F,
+    fragment: B,
+) where V: Varying, F: FragmentShader<V> + ?Sized, B: FnOnce() -> Fragment<V> {
+    let samples = target.offsets.len();
+    let first = ((y - target.bottom) as usize * target.stride + (x - target.left) as usize) * samples;
+    let mut passed = 0;
+    for sample in 0..samples {
+        let depth = depths[sample] + target.state.depth_bias;
+        if covered & (1 << sample) != 0 && target.state.depth_func.passes(depth, target.z[first + sample]) {
+            passed |= 1 << sample;
+        }
+    }
+    if passed == 0 {
+        return;
+    }
+    let mut color = fragment_shader.shade(&fragment());
+    let mut blend_mode = target.state.blend_mode;
+    if coverage < 1.0 {
+        color.a *= coverage;
+        if blend_mode == BlendMode::Opaque {
+            blend_mode = BlendMode::Alpha;
+        }
+    }
+    let opaque = color.as_color();
+    for sample in 0..samples {
+        if passed & (1 << sample) == 0 {
+            continue;
+        }
+        let index = first + sample;
+        target.color[index] = match blend_mode {
+            BlendMode::Opaque => opaque,
+            mode => mode.blend(color, FloatColor::from_color(&target.color[index])).as_color(),
+        };
+        // Fringes of anti-aliased primitives mustn't hide what's drawn behind them later.
+        if target.state.depth_write && coverage >= 0.5 {
+            target.z[index] = depths[sample] + target.state.depth_bias;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fan of overlapping triangles at varying depths, spread over several tiles.
+    fn scene() -> Vec<(ClipVertex<f32>, ClipVertex<f32>, ClipVertex<f32>)> {
+        let vertex = |x: f32, y: f32, z: f32, shade: f32| ClipVertex{
+            position: Vector4{x, y, z, w: 1.0},
+            varyings: shade,
+        };
+        (0..40)
+            .map(|i| {
+                let t = i as f32 / 40.0;
+                let angle = t * 2.0 * std::f32::consts::PI;
+                (
+                    vertex(0.0, 0.0, t - 0.5, t),
+                    vertex(angle.cos(), angle.sin(), 0.5 - t, 1.0 - t),
+                    vertex((angle + 1.5).cos(), (angle + 1.5).sin(), 0.0, 0.5),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tiled_matches_single_threaded() {
+        let shader = |fragment: &Fragment<f32>| {
+            let v = fragment.varyings;
+            FloatColor::from_rgb(v, 1.0 - v, fragment.depth.abs())
+        };
+        for &antialiasing in &[Antialiasing::None, Antialiasing::Multisample(Msaa::X4), Antialiasing::Supersample(2)] {
+            let mut sequential = Rasterizer::with_antialiasing(300, 200, antialiasing);
+            for &triangle in &scene() {
+                sequential.triangle(triangle, &shader);
+            }
+            sequential.resolve();
+            let mut tiled = Rasterizer::with_antialiasing(300, 200, antialiasing);
+            tiled.set_threads(4);
+            tiled.triangles_parallel(&scene(), &shader);
+            tiled.resolve();
+            assert_eq!(tiled.get_color_buffer().cells(), sequential.get_color_buffer().cells());
+            assert_eq!(tiled.get_z_buffer().cells(), sequential.get_z_buffer().cells());
+        }
+    }
+
+    #[test]
+    fn test_shared_edges_are_watertight() {
+        // A fan around an off-grid centre; each triangle is closer than the last, so a pixel
+        // drawn by two of them would be shaded twice.
+        let center = (0.13, -0.07);
+        let count = 7;
+        let triangles: Vec<_> = (0..count)
+            .map(|i| {
+                let corner = |j: usize| {
+                    let angle = j as f32 / count as f32 * 6.2831855;
+                    Vector4{x: center.0 + 0.8 * angle.cos(), y: center.1 + 0.8 * angle.sin(), z: 0.0, w: 1.0}
+                };
+                let z = 0.5 - i as f32 * 0.05;
+                let vertex = |position: Vector4<f32>| ClipVertex{
+                    position: Vector4{z, ..position},
+                    varyings: (),
+                };
+                (vertex(Vector4{x: center.0, y: center.1, z: 0.0, w: 1.0}), vertex(corner(i)), vertex(corner(i + 1)))
+            })
+            .collect();
+        let shaded = std::cell::RefCell::new(Frame::new(97, 61, 0));
+        let shader = |fragment: &Fragment<()>| {
+            let mut shaded = shaded.borrow_mut();
+            let previous = shaded.at(fragment.x, fragment.y).unwrap();
+            shaded.set(fragment.x, fragment.y, previous + 1);
+            FloatColor::from_rgb(1.0, 1.0, 1.0)
+        };
+        let mut rasterizer = Rasterizer::create(97, 61);
+        for &triangle in &triangles {
+            rasterizer.triangle(triangle, &shader);
+        }
+        let shaded = shaded.borrow();
+        assert!(shaded.cells().iter().all(|&count| count <= 1));
+        // The hub of the fan has no holes.
+        let hub_x = ((center.0 + 1.0) / 2.0 * 97.0) as usize;
+        let hub_y = ((1.0 - center.1) / 2.0 * 61.0) as usize;
+        for y in hub_y - 10..hub_y + 10 {
+            for x in hub_x - 10..hub_x + 10 {
+                assert_eq!(shaded.at(x, y), Some(1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_blending_without_depth_write() {
+        let quad = |z: f32| {
+            let vertex = |x: f32, y: f32| ClipVertex{position: Vector4{x, y, z, w: 1.0}, varyings: ()};
+            vec![
+                (vertex(-1.0, -1.0), vertex(1.0, -1.0), vertex(-1.0, 1.0)),
+                (vertex(1.0, -1.0), vertex(1.0, 1.0), vertex(-1.0, 1.0)),
+            ]
+        };
+        let mut rasterizer = Rasterizer::create(8, 8);
+        rasterizer.triangles(&quad(0.5), &|_: &Fragment<()>| FloatColor::from_rgb(1.0, 0.0, 0.0));
+        rasterizer.set_blend_mode(BlendMode::Alpha);
+        rasterizer.set_depth_write(false);
+        let green = |_: &Fragment<()>| FloatColor::from_argb(0.5, 0.0, 1.0, 0.0);
+        rasterizer.triangles(&quad(0.0), &green);
+        // Behind the red quad, so depth tested away.
+        rasterizer.triangles(&quad(0.9), &green);
+        let color = rasterizer.get_color_buffer().at(3, 3).unwrap();
+        assert_eq!((color.r, color.g, color.b), (127, 127, 0));
+        assert!(rasterizer.get_z_buffer().cells().iter().all(|&z| z == 0.5));
+    }
+
+    #[test]
+    fn test_cull_modes_and_winding() {
+        let vertex = |x: f32, y: f32| ClipVertex{position: Vector4{x, y, z: 0.0, w: 1.0}, varyings: ()};
+        let counter_clockwise = (vertex(-1.0, -1.0), vertex(1.0, -1.0), vertex(-1.0, 1.0));
+        let clockwise = (vertex(-1.0, -1.0), vertex(-1.0, 1.0), vertex(1.0, -1.0));
+        // Shades red on front faces and blue on back faces.
+        let shader = |fragment: &Fragment<()>| {
+            if fragment.front_facing { FloatColor::from_rgb(1.0, 0.0, 0.0) } else { FloatColor::from_rgb(0.0, 0.0, 1.0) }
+        };
+        let draw = |state: RenderState, triangle| {
+            let mut rasterizer = Rasterizer::create(8, 8);
+            rasterizer.set_render_state(state);
+            rasterizer.triangle(triangle, &shader);
+            let color = rasterizer.get_color_buffer().at(1, 6).unwrap();
+            (color.r, color.b)
+        };
+        let state = RenderState::new();
+        assert_eq!(draw(state, counter_clockwise), (255, 0));
+        assert_eq!(draw(state, clockwise), (0, 0));
+        let two_sided = RenderState{cull_mode: CullMode::None, ..state};
+        assert_eq!(draw(two_sided, clockwise), (0, 255));
+        let front_culled = RenderState{cull_mode: CullMode::Front, ..state};
+        assert_eq!(draw(front_culled, counter_clockwise), (0, 0));
+        assert_eq!(draw(front_culled, clockwise), (0, 255));
+        let clockwise_front = RenderState{front_face: Winding::Clockwise, ..state};
+        assert_eq!(draw(clockwise_front, clockwise), (255, 0));
+        assert_eq!(draw(clockwise_front, counter_clockwise), (0, 0));
+    }
+
+    #[test]
+    fn test_depth_funcs() {
+        let triangle = |z: f32| {
+            let vertex = |x: f32, y: f32| ClipVertex{position: Vector4{x, y, z, w: 1.0}, varyings: ()};
+            (vertex(-1.0, -1.0), vertex(1.0, -1.0), vertex(-1.0, 1.0))
+        };
+        let white = |_: &Fragment<()>| FloatColor::from_rgb(1.0, 1.0, 1.0);
+        let grey = |_: &Fragment<()>| FloatColor::from_rgb(0.5, 0.5, 0.5);
+        let draw_over = |depth_func: DepthFunc, z: f32| {
+            let mut rasterizer = Rasterizer::create(8, 8);
+            rasterizer.triangle(triangle(0.0), &white);
+            rasterizer.set_render_state(RenderState{depth_func, ..RenderState::new()});
+            rasterizer.triangle(triangle(z), &grey);
+            (rasterizer.get_color_buffer().at(1, 6).unwrap().r, rasterizer.get_z_buffer().at(1, 6).unwrap())
+        };
+        assert_eq!(draw_over(DepthFunc::Less, 0.5), (255, 0.0));
+        assert_eq!(draw_over(DepthFunc::Greater, 0.5), (127, 0.5));
+        assert_eq!(draw_over(DepthFunc::LessEqual, 0.0), (127, 0.0));
+        assert_eq!(draw_over(DepthFunc::Less, 0.0), (255, 0.0));
+        assert_eq!(draw_over(DepthFunc::Always, 0.9), (127, 0.9));
+        assert_eq!(draw_over(DepthFunc::Never, -0.9), (255, 0.0));
+    }
+
+    #[test]
+    fn test_smooth_line_coverage() {
+        // A horizontal line 3.4 pixels wide through the centres of row 8.
+        let vertex = |x: f32, z: f32| ClipVertex{position: Vector4{x, y: -1.0 / 16.0, z, w: 1.0}, varyings: ()};
+        let white = |_: &Fragment<()>| FloatColor::from_rgb(1.0, 1.0, 1.0);
+        let mut rasterizer = Rasterizer::create(16, 16);
+        rasterizer.smooth_line((vertex(-0.5, 0.0), vertex(0.5, 0.0)), 3.4, &white);
+        let column: Vec<u8> = (5..12).map(|y| rasterizer.get_color_buffer().at(8, y).unwrap().r).collect();
+        assert_eq!(column, vec![0, 51, 255, 255, 255, 51, 0]);
+        // The round end reaches past the end point.
+        let row: Vec<u8> = (11..15).map(|x| rasterizer.get_color_buffer().at(x, 8).unwrap().r).collect();
+        assert_eq!(row, vec![255, 255, 178, 0]);
+        // Only pixels at least half covered write depth.
+        assert_eq!(rasterizer.get_z_buffer().at(13, 8).unwrap(), 0.0);
+        assert_eq!(rasterizer.get_z_buffer().at(8, 6).unwrap(), std::f32::MAX);
+
+        // A thinner line behind it is hidden by the depth test.
+        rasterizer.smooth_line(
+            (vertex(-0.5, 0.5), vertex(0.5, 0.5)),
+            1.0,
+            &|_: &Fragment<()>| FloatColor::from_rgb(0.0, 1.0, 0.0),
+        );
+        assert!(rasterizer.get_color_buffer().cells().iter().all(|color| color.g == color.r));
+    }
+
+    #[test]
+    fn test_antialiased_edges() {
+        // A rectangle whose right edge runs down the middle of the second column of pixels.
+        let vertex = |x: f32, y: f32| ClipVertex{position: Vector4{x, y, z: 0.0, w: 1.0}, varyings: ()};
+        let rectangle = [
+            (vertex(-1.0, -1.0), vertex(-0.25, -1.0), vertex(-1.0, 1.0)),
+            (vertex(-0.25, -1.0), vertex(-0.25, 1.0), vertex(-1.0, 1.0)),
+        ];
+        let white = |_: &Fragment<()>| FloatColor::from_rgb(1.0, 1.0, 1.0);
+        for &antialiasing in &[Antialiasing::Multisample(Msaa::X4), Antialiasing::Supersample(2)] {
+            let mut rasterizer = Rasterizer::with_antialiasing(4, 4, antialiasing);
+            rasterizer.triangles(&rectangle, &white);
+            rasterizer.resolve();
+            let colors = rasterizer.get_color_buffer();
+            assert_eq!((colors.width(), colors.height()), (4, 4));
+            for y in 0..4 {
+                let row: Vec<u8> = (0..4).map(|x| colors.at(x, y).unwrap().r).collect();
+                assert_eq!(row, vec![255, 128, 0, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_multisampling_shades_once_per_pixel() {
+        let vertex = |x: f32, y: f32| ClipVertex{position: Vector4{x, y, z: 0.0, w: 1.0}, varyings: ()};
+        let covering = (vertex(-1.0, -1.0), vertex(3.0, -1.0), vertex(-1.0, 3.0));
+        let shade_count = |antialiasing: Antialiasing| {
+            let count = std::cell::Cell::new(0);
+            let mut rasterizer = Rasterizer::with_antialiasing(4, 4, antialiasing);
+            rasterizer.triangle(covering, &|_: &Fragment<()>| {
+                count.set(count.get() + 1);
+                FloatColor::from_rgb(1.0, 1.0, 1.0)
+            });
+            count.get()
+        };
+        assert_eq!(shade_count(Antialiasing::None), 16);
+        assert_eq!(shade_count(Antialiasing::Multisample(Msaa::X8)), 16);
+        assert_eq!(shade_count(Antialiasing::Supersample(2)), 64);
+    }
+
+    #[test]
+    fn test_multisampling_shades_on_the_triangle() {
+        // Pixels along the slanted edge have samples on the triangle but centres off it, where
+        // the varying would extrapolate below 0.
+        let vertex = |x: f32, y: f32, shade: f32| ClipVertex{position: Vector4{x, y, z: 0.0, w: 1.0}, varyings: shade};
+        let mut rasterizer = Rasterizer::with_antialiasing(16, 16, Antialiasing::Multisample(Msaa::X4));
+        let shades = std::cell::RefCell::new(vec![]);
+        rasterizer.triangle(
+            (vertex(-0.9, -0.8, 0.0), vertex(0.9, -0.9, 1.0), vertex(0.3, 0.95, 0.0)),
+            &|fragment: &Fragment<f32>| {
+                shades.borrow_mut().push(fragment.varyings);
+                FloatColor::from_rgb(1.0, 1.0, 1.0)
+            },
+        );
+        let shades = shades.into_inner();
+        assert!(!shades.is_empty());
+        for &shade in &shades {
+            assert!(shade >= -1e-4 && shade <= 1.0 + 1e-4, "shaded with {}", shade);
+        }
+    }
+
+    #[test]
+    fn test_samples_pixel_centres() {
+        // A right triangle covering exactly the top-left half of a 4x4 screen.
+        let vertex = |x: f32, y: f32| ClipVertex{position: Vector4{x, y, z: 0.0, w: 1.0}, varyings: ()};
+        let mut rasterizer = Rasterizer::create(4, 4);
+        rasterizer.triangle(
+            (vertex(-1.0, 1.0), vertex(-1.0, -1.0), vertex(1.0, 1.0)),
+            &|_: &Fragment<()>| FloatColor::from_rgb(1.0, 1.0, 1.0),
+        );
+        let covered: Vec<bool> = rasterizer.get_color_buffer().cells().iter()
+            .map(|color| color.r == 255)
+            .collect();
+        assert_eq!(covered, vec![
+            true, true, true, false,
+            true, true, false, false,
+            true, false, false, false,
+            false, false, false, false,
+        ]);
+    }
+}
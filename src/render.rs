@@ -1,46 +1,81 @@
 use cgmath::*;
+use collision::{Aabb, Aabb3, Frustum, Relation, Sphere};
 use math::*;
 use geometry::*;
 use rasterizer::*;
 use light::*;
 use colors::*;
 use materials::*;
+use shaders::*;
 use textures::*;
 use std::collections::HashMap;
-use sdl_utils::*;
-use sdl2::{
-    render::Canvas,
-    video::Window,
-};
+use output::*;
 use camera::*;
+use shadows::*;
+use targets::*;
 
-// Right now, Renderer takes ownership of rasterizer, canvas, and textures.
+// Right now, Renderer takes ownership of rasterizer, output target, and textures.
 // Not sure if that should be the case.
-pub struct Renderer {
+pub struct Renderer<T: OutputTarget> {
     // TODO: no pub, also should Renderer be responsible for this stuff?
     pub rasterizer: Rasterizer,
-    pub canvas: Canvas<Window>,
+    pub output: T,
 
-    // TODO: replace with some kind of vertex-shader equivalent
-    pub model_view: Matrix4<f32>,
     pub world_to_view_matrix: Matrix4<f32>,
     pub projection: Matrix4<f32>,
+    pub camera_position: Vector3<f32>,
 
     pub lighting: Lighting,
     // TODO: HashMap<usize, Texture>
     pub textures: HashMap<usize, Texture>,
     pub material: Material,
+    pub specular_model: SpecularModel,
+    pub shadow_settings: ShadowSettings,
+    // One per light, in the same order as `lighting.lights`.
+    pub shadow_maps: Vec<Option<ShadowMap>>,
+    pub polygon_mode: PolygonMode,
+    transparent_draws: Vec<TransparentDraw>,
 }
 
-impl Renderer {
+/// Which parts of each triangle get drawn.
+#[derive(Clone, Copy, Debug)]
+pub enum PolygonMode {
+    Fill,
+    /// Triangle edges only, shaded as the surface would be.
+    Line,
+    /// Triangle corners only.
+    Point,
+    /// Filled triangles with their edges drawn over them in a flat color. The edges are pulled
+    /// `depth_bias` towards the camera in NDC so the fill doesn't hide them; around -0.0001
+    /// suits the default projection.
+    FillAndLine{color: FloatColor, depth_bias: f32},
+}
+
+// A blended mesh waiting to be sorted against the others, with the state it was drawn with. Its
+// vertices are shaded when it's queued, and it's the triangles' indices into them that are
+// sorted.
+struct TransparentDraw {
+    vertices: Vec<ClipVertex<StandardVaryings>>,
+    triangles: Vec<(usize, usize, usize)>,
+    // World-space centre of the mesh's bounds, used to order draws.
+    center: Vector3<f32>,
+    clip: bool,
+    material: Material,
+    state: RenderState,
+    polygon_mode: PolygonMode,
+}
 
-    pub fn new(rasterizer: Rasterizer, canvas: Canvas<Window>) -> Self {
+impl <T: OutputTarget> Renderer<T> {
+
+    pub fn new(rasterizer: Rasterizer, output: T) -> Self {
+        let aspect = rasterizer.get_color_buffer().width() as f32
+            / rasterizer.get_color_buffer().height() as f32;
         Renderer{
             rasterizer,
-            canvas,
-            model_view: Matrix4::identity(),
+            output,
             world_to_view_matrix: Matrix4::identity(),
-            projection: Matrix4::from(perspective(Deg(70.0), 1000.0 / 800.0, 0.1, 100.0)),
+            projection: Projection::Perspective{fov: 70.0, aspect, near: 0.1, far: 100.0}.matrix(),
+            camera_position: Vector3{x: 0.0, y: 0.0, z: 0.0},
             lighting: Lighting{
                 lights: vec![
                     Light::point_light(Vector3{x: 1.0, y: 1.0, z: 1.0}),
@@ -52,14 +87,30 @@ impl Renderer {
                 diffuse: FloatColor::from_rgb(1.0, 1.0, 1.0),
                 specular: FloatColor::from_rgb(1.0, 1.0, 1.0),
                 ambient: FloatColor::from_rgb(1.0, 1.0, 1.0),
+                shininess: 32.0,
+                opacity: 1.0,
                 texture: None,
             },
+            specular_model: SpecularModel::BlinnPhong,
+            shadow_settings: ShadowSettings::new(),
+            shadow_maps: vec![],
+            polygon_mode: PolygonMode::Fill,
+            transparent_draws: vec![],
         }
     }
 
     pub fn set_from_camera(&mut self, camera: &Camera) {
         self.world_to_view_matrix = camera.eye().invert().unwrap();
         self.projection = camera.projection();
+        self.camera_position = camera.position();
+    }
+
+    pub fn set_polygon_mode(&mut self, polygon_mode: PolygonMode) {
+        self.polygon_mode = polygon_mode;
+    }
+
+    pub fn set_specular_model(&mut self, specular_model: SpecularModel) {
+        self.specular_model = specular_model;
     }
 
     pub fn set_texture(&mut self, index: usize, texture: Texture) {
@@ -70,130 +121,917 @@ impl Renderer {
         self.material = material
     }
 
+    /// Sets the culling, depth testing and blending of subsequent draws.
+    pub fn set_render_state(&mut self, state: RenderState) {
+        self.rasterizer.set_render_state(state);
+    }
+
+    /// Sets how subsequent draws combine with what's already drawn. Meshes drawn with `mesh` or
+    /// `indexed_mesh` in any mode but `BlendMode::Opaque` are held back until `present` and
+    /// then drawn farthest first.
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.rasterizer.set_blend_mode(blend_mode);
+    }
+
+    pub fn set_depth_write(&mut self, depth_write: bool) {
+        self.rasterizer.set_depth_write(depth_write);
+    }
+
+    /// Renders a shadow map for every light with `casts_shadows` set, with `casters` (each with
+    /// its object-to-world transform) as the occluders. The maps are used by `mesh` and
+    /// `triangle` until this is called again, so it needs calling whenever the casters or lights
+    /// move.
+    pub fn render_shadow_maps<A: Copy>(&mut self, casters: &[(&Mesh<A>, Matrix4<f32>)]) {
+        let settings = self.shadow_settings;
+        let pool = self.rasterizer.pool();
+        self.shadow_maps = self.lighting.lights.iter()
+            .map(|light| {
+                if light.casts_shadows {
+                    ShadowMap::render_on(light, casters, settings, Some(pool.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+    }
+
+    /// The combined world-to-clip-space transform for the current camera, for use by vertex
+    /// shaders.
+    pub fn world_to_clip_matrix(&self) -> Matrix4<f32> {
+        self.projection * self.world_to_view_matrix
+    }
+
+    /// The camera's view volume as planes in world space, or None for a degenerate projection.
+    pub fn frustum(&self) -> Option<Frustum<f32>> {
+        Frustum::from_matrix4(self.world_to_clip_matrix())
+    }
+
+    // None if an object with these bounds can't be seen at all, otherwise whether any of it
+    // might fall outside the view volume. Without bounds nothing is known, so it's clipped.
+    fn needs_clipping(&self, bounds: Option<Bounds>, object_to_world: Matrix4<f32>) -> Option<bool> {
+        let bounds = match bounds {
+            Some(bounds) => bounds,
+            None => return Some(true),
+        };
+        match self.frustum() {
+            Some(frustum) => match frustum_relation(&frustum, bounds.sphere, bounds.aabb, object_to_world) {
+                Relation::In => Some(false),
+                Relation::Cross => Some(true),
+                Relation::Out => None,
+            },
+            None => Some(true),
+        }
+    }
+
+    /// Draws `mesh` with the built-in lighting, placed in the world by `object_to_world`.
+    pub fn mesh<A: Varying>(&mut self, mesh: &Mesh<A>, object_to_world: Matrix4<f32>) {
+        self.mesh_with_bounds(mesh, mesh.bounds(), object_to_world);
+    }
+
+    /// Like `mesh`, but with the mesh's bounds, from `Mesh::bounds`, supplied rather than found
+    /// on every call, so culling an off-screen mesh costs next to nothing. With None the bounds
+    /// are taken as unknown, and the mesh is drawn without culling and clipped.
     // todo: perform lighting calculations in camera space
-    pub fn mesh(&mut self, mesh: &Mesh) {
-        for tri in &mesh.vertices {
-            self.triangle(tri.0, tri.1, tri.2);
-        }
-    }
-
-    // Improvements: shouldn't have to compute inverses every time
-    pub fn triangle(&mut self, v0: Vertex3, v1: Vertex3, v2: Vertex3) {
-        // First we need to transform our vertices to clip space, for clipping. Then,
-        // we'll need to transform the resulting vertices back to camera and world space,
-        // because our rasterizer needs all three to work (maybe it shouldn't?).
-        let world_to_clip_space = self.projection * self.world_to_view_matrix;
-        let clip0 = v0.to_vertex4(1.0).transformed(world_to_clip_space);
-        let clip1 = v1.to_vertex4(1.0).transformed(world_to_clip_space);
-        let clip2 = v2.to_vertex4(1.0).transformed(world_to_clip_space);
-        let tris = clip_triangle(
-            clip0,
-            clip1,
-            clip2,
-            self.projection.invert().unwrap(),
+    pub fn mesh_with_bounds<A: Varying>(&mut self, mesh: &Mesh<A>, bounds: Option<Bounds>, object_to_world: Matrix4<f32>) {
+        let clip = match self.needs_clipping(bounds, object_to_world) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let vertex_shader = Unattributed(StandardVertexShader::with_model(self.world_to_clip_matrix(), object_to_world));
+        if self.rasterizer.blend_mode() != BlendMode::Opaque {
+            let mut vertices = Vec::with_capacity(mesh.vertices.len() * 3);
+            for &(v0, v1, v2) in &mesh.vertices {
+                vertices.push(vertex_shader.shade(&v0));
+                vertices.push(vertex_shader.shade(&v1));
+                vertices.push(vertex_shader.shade(&v2));
+            }
+            let triangles = (0..mesh.vertices.len()).map(|i| (i * 3, i * 3 + 1, i * 3 + 2)).collect();
+            self.queue_transparent(vertices, triangles, bounds, object_to_world, clip);
+            return;
+        }
+        let fragment_shader = PhongFragmentShader{
+            lighting: &self.lighting,
+            textures: &self.textures,
+            material: &self.material,
+            shadow_maps: &self.shadow_maps,
+            camera_position: self.camera_position,
+            model: self.specular_model,
+        };
+        draw_triangles(&mut self.rasterizer, &mesh.vertices, &vertex_shader, &Parallel(&fragment_shader), clip, self.polygon_mode);
+    }
+
+    pub fn triangle<A: Varying>(&mut self, v0: Vertex3<A>, v1: Vertex3<A>, v2: Vertex3<A>) {
+        let vertex_shader = Unattributed(StandardVertexShader::new(self.world_to_clip_matrix()));
+        let fragment_shader = PhongFragmentShader{
+            lighting: &self.lighting,
+            textures: &self.textures,
+            material: &self.material,
+            shadow_maps: &self.shadow_maps,
+            camera_position: self.camera_position,
+            model: self.specular_model,
+        };
+        draw_triangles(
+            &mut self.rasterizer,
+            &vec![(v0, v1, v2)],
+            &vertex_shader,
+            &Parallel(&fragment_shader),
+            true,
+            self.polygon_mode,
         );
-        for tri in tris {
-            self.render_clip_triangle(tri.0, tri.1, tri.2);
-        }
-    }
-
-    fn render_clip_triangle(&mut self, v0: Vertex4, v1: Vertex4, v2: Vertex4) {
-        let clip_to_camera_space = self.projection.invert().unwrap();
-        let camera_to_world_space = self.world_to_view_matrix.invert().unwrap();
-        let camera0 = v0.transformed(clip_to_camera_space);
-        let camera1 = v1.transformed(clip_to_camera_space);
-        let camera2 = v2.transformed(clip_to_camera_space);
-        let world0 = camera0.transformed(camera_to_world_space);
-        let world1 = camera1.transformed(camera_to_world_space);
-        let world2 = camera2.transformed(camera_to_world_space);
-        let perspective_adjusted0 = v0.perspective_adjusted();
-        let perspective_adjusted1 = v1.perspective_adjusted();
-        let perspective_adjusted2 = v2.perspective_adjusted();
-
-        let lights = &self.lighting.lights;
-        let ambient = &self.lighting.ambient;
-        let textures = &self.textures;
-        let material = &self.material;
-
-        self.rasterizer.triangle(
-            (world0, world1, world2),
-            (camera0, camera1, camera2),
-            (perspective_adjusted0, perspective_adjusted1, perspective_adjusted2),
-            &|coords, normals, uvs| {
-                process_fragment(
-                    coords,
-                    normals,
-                    uvs,
-                    lights,
-                    ambient,
-                    textures,
-                    material,
-                )
-            },
+    }
+
+    /// Draws a world-space line segment `width` pixels wide in a flat color, anti-aliased and
+    /// depth tested. Meant for debug drawing such as axes, bounds and normals.
+    pub fn line(&mut self, from: Vector3<f32>, to: Vector3<f32>, color: FloatColor, width: f32) {
+        self.polyline(&[from, to], color, width);
+    }
+
+    /// Draws connected line segments through `points`, as `line` does.
+    pub fn polyline(&mut self, points: &[Vector3<f32>], color: FloatColor, width: f32) {
+        let world_to_clip = self.world_to_clip_matrix();
+        let to_clip = |point: &Vector3<f32>| ClipVertex{position: world_to_clip * point.extend(1.0), varyings: ()};
+        let fragment_shader = |_: &Fragment<()>| color;
+        // Joints are covered by both of their segments at the same depth; let the second one
+        // through so the joint isn't left with only the first segment's faded cap.
+        let state = self.rasterizer.render_state();
+        if state.depth_func == DepthFunc::Less {
+            self.rasterizer.set_render_state(RenderState{depth_func: DepthFunc::LessEqual, ..state});
+        }
+        for segment in points.windows(2) {
+            if let Some(clipped) = clip_line(to_clip(&segment[0]), to_clip(&segment[1])) {
+                self.rasterizer.smooth_line(clipped, width, &fragment_shader);
+            }
+        }
+        self.rasterizer.set_render_state(state);
+    }
+
+    /// Draws a round, anti-aliased dot `size` pixels across at a world-space position, if it's
+    /// in view.
+    pub fn point(&mut self, position: Vector3<f32>, color: FloatColor, size: f32) {
+        let vertex = ClipVertex{position: self.world_to_clip_matrix() * position.extend(1.0), varyings: ()};
+        if is_in_box(&vertex) {
+            self.rasterizer.smooth_point(vertex, size, &|_: &Fragment<()>| color);
+        }
+    }
+
+    /// Like `mesh`, but each vertex is transformed once however many triangles share it.
+    pub fn indexed_mesh<A: Varying>(&mut self, mesh: &IndexedMesh<A>, object_to_world: Matrix4<f32>) {
+        self.indexed_mesh_with_bounds(mesh, mesh.bounds(), object_to_world);
+    }
+
+    /// Like `mesh_with_bounds`, for indexed meshes.
+    pub fn indexed_mesh_with_bounds<A: Varying>(
+        &mut self,
+        mesh: &IndexedMesh<A>,
+        bounds: Option<Bounds>,
+        object_to_world: Matrix4<f32>,
+    ) {
+        let clip = match self.needs_clipping(bounds, object_to_world) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let vertex_shader = Unattributed(StandardVertexShader::with_model(self.world_to_clip_matrix(), object_to_world));
+        if self.rasterizer.blend_mode() != BlendMode::Opaque {
+            let vertices = mesh.vertices.iter().map(|vertex| vertex_shader.shade(vertex)).collect();
+            self.queue_transparent(vertices, mesh.indices.clone(), bounds, object_to_world, clip);
+            return;
+        }
+        let fragment_shader = PhongFragmentShader{
+            lighting: &self.lighting,
+            textures: &self.textures,
+            material: &self.material,
+            shadow_maps: &self.shadow_maps,
+            camera_position: self.camera_position,
+            model: self.specular_model,
+        };
+        draw_indexed_triangles(
+            &mut self.rasterizer,
+            &mesh.vertices,
+            &mesh.indices,
+            &vertex_shader,
+            &Parallel(&fragment_shader),
+            clip,
+            self.polygon_mode,
+        );
+    }
+
+    pub fn draw_indexed_mesh<A, VS, FS>(
+        &mut self,
+        mesh: &IndexedMesh<A>,
+        vertex_shader: &VS,
+        fragment_shader: &FS,
+    ) where VS: VertexShader<Vertex3<A>>, FS: FragmentShader<VS::Varyings> {
+        draw_indexed_triangles(
+            &mut self.rasterizer,
+            &mesh.vertices,
+            &mesh.indices,
+            vertex_shader,
+            &Serial(fragment_shader),
+            true,
+            self.polygon_mode,
         );
     }
 
+    /// Draws a mesh with a user-supplied shader pair instead of the built-in lighting. The
+    /// shaders can hold any state, such as a `Cell`, so they're run on the calling thread only.
+    pub fn draw_mesh<A, VS, FS>(&mut self, mesh: &Mesh<A>, vertex_shader: &VS, fragment_shader: &FS)
+        where VS: VertexShader<Vertex3<A>>, FS: FragmentShader<VS::Varyings> {
+        draw_triangles(&mut self.rasterizer, &mesh.vertices, vertex_shader, &Serial(fragment_shader), true, self.polygon_mode);
+    }
+
+    /// Draws triangles of arbitrary vertex type; the vertex shader is responsible for turning
+    /// each vertex into clip space.
+    pub fn draw_triangles<I, VS, FS>(
+        &mut self,
+        triangles: &Vec<(I, I, I)>,
+        vertex_shader: &VS,
+        fragment_shader: &FS,
+    ) where VS: VertexShader<I>, FS: FragmentShader<VS::Varyings> {
+        draw_triangles(&mut self.rasterizer, triangles, vertex_shader, &Serial(fragment_shader), true, self.polygon_mode);
+    }
+
+    fn queue_transparent(
+        &mut self,
+        vertices: Vec<ClipVertex<StandardVaryings>>,
+        triangles: Vec<(usize, usize, usize)>,
+        bounds: Option<Bounds>,
+        object_to_world: Matrix4<f32>,
+        clip: bool,
+    ) {
+        let center = match bounds {
+            Some(bounds) => (object_to_world * bounds.sphere.center.to_vec().extend(1.0)).truncate(),
+            // Without bounds, the average vertex will do.
+            None => vertices.iter().fold(Vector3::zero(), |sum, vertex| sum + vertex.varyings.world_position)
+                / std::cmp::max(vertices.len(), 1) as f32,
+        };
+        self.transparent_draws.push(TransparentDraw{
+            vertices,
+            triangles,
+            center,
+            clip,
+            material: self.material,
+            state: self.rasterizer.render_state(),
+            polygon_mode: self.polygon_mode,
+        });
+    }
+
+    /// Draws the blended meshes held back since the last flush, farthest first, so that each
+    /// blends over what's behind it. `present` calls this before showing the frame.
+    pub fn flush_transparent(&mut self) {
+        let mut draws = std::mem::replace(&mut self.transparent_draws, vec![]);
+        let world_to_view = self.world_to_view_matrix;
+        // The camera looks down -z, so the farthest points have the lowest z. Degenerate
+        // transforms can give NaN, which is taken as farthest.
+        let view_depth = |position: Vector3<f32>| {
+            let z = (world_to_view * position.extend(1.0)).z;
+            if z.is_nan() { std::f32::NEG_INFINITY } else { z }
+        };
+        draws.sort_by(|a, b| view_depth(a.center).total_cmp(&view_depth(b.center)));
+        let state = self.rasterizer.render_state();
+        for draw in &mut draws {
+            // Sorting the triangles too gets convex meshes right from any direction.
+            let vertices = &draw.vertices;
+            let triangle_depth = |&(i0, i1, i2): &(usize, usize, usize)| {
+                view_depth((
+                    vertices[i0].varyings.world_position
+                        + vertices[i1].varyings.world_position
+                        + vertices[i2].varyings.world_position
+                ) / 3.0)
+            };
+            draw.triangles.sort_by(|a, b| triangle_depth(a).total_cmp(&triangle_depth(b)));
+            let triangles = draw.triangles.iter()
+                .map(|&(i0, i1, i2)| (vertices[i0], vertices[i1], vertices[i2]))
+                .collect();
+            self.rasterizer.set_render_state(draw.state);
+            let fragment_shader = PhongFragmentShader{
+                lighting: &self.lighting,
+                textures: &self.textures,
+                material: &draw.material,
+                shadow_maps: &self.shadow_maps,
+                camera_position: self.camera_position,
+                model: self.specular_model,
+            };
+            draw_shaded_triangles(
+                &mut self.rasterizer,
+                triangles,
+                &Parallel(&fragment_shader),
+                draw.clip,
+                draw.polygon_mode,
+            );
+        }
+        self.rasterizer.set_render_state(state);
+    }
+
+    /// Runs `pass` with everything it draws going to `target`, which is cleared first, rather than
+    /// the screen. The pass starts with the current render state, and can set its own camera;
+    /// the camera is put back afterwards. Transparent meshes queued during the pass are drawn
+    /// into the target before it ends.
+    pub fn render_to<F: FnOnce(&mut Self)>(&mut self, target: &mut RenderTarget, pass: F) {
+        let (world_to_view_matrix, projection, camera_position) =
+            (self.world_to_view_matrix, self.projection, self.camera_position);
+        // Anything already queued belongs on the screen.
+        let transparent_draws = std::mem::replace(&mut self.transparent_draws, vec![]);
+        let state = self.rasterizer.render_state();
+        // The target's parallel draws can use the screen's worker threads.
+        if target.rasterizer.threads() == self.rasterizer.threads() {
+            target.rasterizer.share_pool(self.rasterizer.pool());
+        }
+        std::mem::swap(&mut self.rasterizer, &mut target.rasterizer);
+        self.rasterizer.clear();
+        self.rasterizer.set_render_state(state);
+
+        pass(self);
+        self.flush_transparent();
+        self.rasterizer.resolve();
+
+        std::mem::swap(&mut self.rasterizer, &mut target.rasterizer);
+        self.transparent_draws = transparent_draws;
+        self.world_to_view_matrix = world_to_view_matrix;
+        self.projection = projection;
+        self.camera_position = camera_position;
+    }
+
     pub fn present(&mut self) {
-        render_to_canvas(&mut self.canvas, self.rasterizer.get_color_buffer());
+        self.flush_transparent();
+        self.rasterizer.resolve();
+        self.output.present(self.rasterizer.get_color_buffer());
         self.rasterizer.clear();
     }
 }
 
-pub struct RenderFragmentContext {
+/// Where an object with the given object-space bounds lies relative to `frustum` once placed by
+/// `object_to_world`. The sphere is checked first as it's cheap to transform; the box settles
+/// what the sphere can't.
+pub fn frustum_relation(
+    frustum: &Frustum<f32>,
+    sphere: Sphere<f32>,
+    bounds: Aabb3<f32>,
+    object_to_world: Matrix4<f32>,
+) -> Relation {
+    let scale = object_to_world.x.truncate().magnitude()
+        .max(object_to_world.y.truncate().magnitude())
+        .max(object_to_world.z.truncate().magnitude());
+    let world_sphere = Sphere{
+        center: Point3::from_homogeneous(object_to_world * sphere.center.to_homogeneous()),
+        radius: sphere.radius * scale,
+    };
+    match frustum.contains(&world_sphere) {
+        Relation::Cross => frustum.contains(&bounds.transform(&object_to_world)),
+        relation => relation,
+    }
 }
 
-fn process_fragment(
-    world_coordinates: &Vector4<f32>,
-    world_normals: &Vector4<f32>,
-    uvs: &Vector2<f32>,
-    lights: &Vec<Light>,
-    ambient: &FloatColor,
-    textures: &HashMap<usize, Texture>,
-    material: &Material,
-) -> FloatColor {
-    let texture = material.texture
-        .and_then(|index| textures.get(&index));
-    let texture_color = match texture {
-        Some(ref t) => {
-            FloatColor::from_sdl_color(&t.sample(uvs.x, uvs.y, TextureFilterMode::Bilinear))
-        },
-        None => FloatColor::from_rgb(1.0, 1.0, 1.0),
+// Draws hand their fragment shader down wrapped in `Serial` or `Parallel`, which decides how
+// filled triangles reach the rasterizer: shaders that can be shared between threads are run on
+// its worker threads, and any others on the calling thread.
+pub(crate) trait Fill<V: Varying>: FragmentShader<V> {
+    fn fill(&self, rasterizer: &mut Rasterizer, triangles: &[(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)]);
+}
+
+pub(crate) struct Serial<'a, F: 'a + ?Sized>(pub &'a F);
+
+pub(crate) struct Parallel<'a, F: 'a + ?Sized>(pub &'a F);
+
+impl <'a, V, F> FragmentShader<V> for Serial<'a, F> where F: FragmentShader<V> + ?Sized {
+    fn shade(&self, fragment: &Fragment<V>) -> FloatColor {
+        self.0.shade(fragment)
+    }
+}
+
+impl <'a, V: Varying, F> Fill<V> for Serial<'a, F> where F: FragmentShader<V> + ?Sized {
+    fn fill(&self, rasterizer: &mut Rasterizer, triangles: &[(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)]) {
+        rasterizer.triangles(triangles, self.0);
+    }
+}
+
+impl <'a, V, F> FragmentShader<V> for Parallel<'a, F> where F: FragmentShader<V> + ?Sized {
+    fn shade(&self, fragment: &Fragment<V>) -> FloatColor {
+        self.0.shade(fragment)
+    }
+}
+
+impl <'a, V: Varying + Sync, F> Fill<V> for Parallel<'a, F> where F: FragmentShader<V> + Sync + ?Sized {
+    fn fill(&self, rasterizer: &mut Rasterizer, triangles: &[(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)]) {
+        rasterizer.triangles_parallel(triangles, self.0);
+    }
+}
+
+// The built-in shaders don't read mesh attributes, so they're left behind, which lets meshes
+// with attributes of any type be shaded in parallel.
+struct Unattributed(StandardVertexShader);
+
+impl <A: Varying> VertexShader<Vertex3<A>> for Unattributed {
+    type Varyings = StandardVaryings;
+
+    fn shade(&self, vertex: &Vertex3<A>) -> ClipVertex<StandardVaryings> {
+        self.0.shade(&vertex.with_attributes(()))
+    }
+}
+
+// Triangles known to be entirely inside the view volume can skip clipping with `clip: false`.
+pub(crate) fn draw_triangles<I, VS, FS>(
+    rasterizer: &mut Rasterizer,
+    triangles: &Vec<(I, I, I)>,
+    vertex_shader: &VS,
+    fragment_shader: &FS,
+    clip: bool,
+    polygon_mode: PolygonMode,
+) where VS: VertexShader<I>, FS: Fill<VS::Varyings> {
+    let shaded = triangles.iter()
+        .map(|tri| (vertex_shader.shade(&tri.0), vertex_shader.shade(&tri.1), vertex_shader.shade(&tri.2)))
+        .collect();
+    draw_shaded_triangles(rasterizer, shaded, fragment_shader, clip, polygon_mode);
+}
+
+// Vertex shader outputs are kept in a post-transform cache indexed like the vertex buffer, so
+// shared vertices are only shaded once, and vertices no triangle uses aren't shaded at all.
+pub(crate) fn draw_indexed_triangles<I, VS, FS>(
+    rasterizer: &mut Rasterizer,
+    vertices: &Vec<I>,
+    indices: &Vec<(usize, usize, usize)>,
+    vertex_shader: &VS,
+    fragment_shader: &FS,
+    clip: bool,
+    polygon_mode: PolygonMode,
+) where VS: VertexShader<I>, FS: Fill<VS::Varyings> {
+    let mut cache: Vec<Option<ClipVertex<VS::Varyings>>> = vec![None; vertices.len()];
+    let mut shade = |index: usize| {
+        *cache[index].get_or_insert_with(|| vertex_shader.shade(&vertices[index]))
     };
-    let color_from_lights = lights.iter()
-        .map(|light| {
-            process_fragment_light(
-                &world_coordinates,
-                &world_normals,
-                light,
-                material,
-            )
-        })
-        .sum();
-    let ambient_color = FloatColor::multiply_colors(ambient, &material.ambient);
-    let total_light_color = ambient_color + color_from_lights;
-
-    let net_color = FloatColor::multiply_colors(&texture_color, &total_light_color);
-    net_color.clamped()
-}
-
-fn process_fragment_light(
-    world_coordinates: &Vector4<f32>,
-    world_normals: &Vector4<f32>,
-    light: &Light,
-    material: &Material,
-) -> FloatColor {
-    let intensity = match light.light_type {
-        LightType::Directional(ref directional_light) => {
-            let intensity = directional_light.direction.dot(world_normals.truncate());
-            if intensity < 0.0 { 0.0 } else { intensity }
-        }
-        LightType::Point(ref point_light) => {
-            let ray = point_light.position - world_coordinates.truncate();
-            let distance = ray.magnitude();
-            let normalized_ray = ray / distance;
-            let intensity = normalized_ray.dot(world_normals.truncate()) / (distance * distance);
-            if intensity < 0.0 { 0.0 } else { intensity }
+    let shaded = indices.iter()
+        .map(|&(i0, i1, i2)| (shade(i0), shade(i1), shade(i2)))
+        .collect();
+    draw_shaded_triangles(rasterizer, shaded, fragment_shader, clip, polygon_mode);
+}
+
+fn draw_shaded_triangles<V, FS>(
+    rasterizer: &mut Rasterizer,
+    triangles: Vec<(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)>,
+    fragment_shader: &FS,
+    clip: bool,
+    polygon_mode: PolygonMode,
+) where V: Varying, FS: Fill<V> {
+    match polygon_mode {
+        PolygonMode::Fill => fill(rasterizer, triangles, fragment_shader, clip),
+        PolygonMode::Line => outline(rasterizer, &triangles, fragment_shader),
+        PolygonMode::Point => corners(rasterizer, &triangles, fragment_shader),
+        PolygonMode::FillAndLine{color, depth_bias} => {
+            let state = rasterizer.render_state();
+            fill(rasterizer, triangles.clone(), fragment_shader, clip);
+            rasterizer.set_render_state(RenderState{depth_bias: state.depth_bias + depth_bias, ..state});
+            outline(rasterizer, &triangles, &|_: &Fragment<V>| color);
+            rasterizer.set_render_state(state);
+        },
+    }
+}
+
+fn fill<V, FS>(
+    rasterizer: &mut Rasterizer,
+    triangles: Vec<(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)>,
+    fragment_shader: &FS,
+    clip: bool,
+) where V: Varying, FS: Fill<V> {
+    if clip {
+        let clipped: Vec<_> = triangles.into_iter()
+            .flat_map(|(v0, v1, v2)| clip_triangle(v0, v1, v2))
+            .collect();
+        fragment_shader.fill(rasterizer, &clipped);
+    } else {
+        fragment_shader.fill(rasterizer, &triangles);
+    }
+}
+
+// The part of a triangle that's in view, or None if it's out of view or culled.
+fn visible_polygon<V: Varying>(
+    rasterizer: &Rasterizer,
+    &(v0, v1, v2): &(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>),
+) -> Option<Vec<ClipVertex<V>>> {
+    let polygon = clip_in_box(&vec![v0, v1, v2]);
+    if polygon.len() < 3 || rasterizer.culls(&(polygon[0], polygon[1], polygon[2])) {
+        return None;
+    }
+    Some(polygon)
+}
+
+// Edges are clipped on their own, rather than drawing the clipped polygon's outline, so that
+// clipping doesn't add edges of its own.
+fn outline<V, FS>(
+    rasterizer: &mut Rasterizer,
+    triangles: &Vec<(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)>,
+    fragment_shader: &FS,
+) where V: Varying, FS: FragmentShader<V> + ?Sized {
+    for triangle in triangles {
+        if visible_polygon(rasterizer, triangle).is_none() {
+            continue;
         }
-    };
-    FloatColor::multiply_colors(&material.diffuse, &light.color) * intensity
+        let (v0, v1, v2) = *triangle;
+        for &(from, to) in &[(v0, v1), (v1, v2), (v2, v0)] {
+            if let Some(segment) = clip_line(from, to) {
+                rasterizer.line(segment, fragment_shader);
+            }
+        }
+    }
+}
+
+fn corners<V, FS>(
+    rasterizer: &mut Rasterizer,
+    triangles: &Vec<(ClipVertex<V>, ClipVertex<V>, ClipVertex<V>)>,
+    fragment_shader: &FS,
+) where V: Varying, FS: FragmentShader<V> + ?Sized {
+    for triangle in triangles {
+        if visible_polygon(rasterizer, triangle).is_none() {
+            continue;
+        }
+        let (v0, v1, v2) = *triangle;
+        for vertex in &[v0, v1, v2] {
+            if is_in_box(vertex) {
+                rasterizer.point(*vertex, fragment_shader);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frame::Frame;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_indexed_mesh_shades_each_vertex_once() {
+        // A 6x6 grid of quads, with interior vertices shared by up to six triangles.
+        let grid_vertices: Vec<Vertex3> = (0..49)
+            .map(|i| Vertex3{
+                position: Vector3{x: (i % 7) as f32 / 3.0 - 1.0, y: (i / 7) as f32 / 3.0 - 1.0, z: 0.0},
+                uv: Vector2{x: 0.0, y: 0.0},
+                normal: Vector3{x: (i % 7) as f32 / 6.0, y: (i / 7) as f32 / 6.0, z: 1.0},
+                attributes: (),
+            })
+            .collect();
+        let grid_indices: Vec<(usize, usize, usize)> = (0..36)
+            .flat_map(|quad| {
+                let corner = quad / 6 * 7 + quad % 6;
+                vec![(corner, corner + 1, corner + 7), (corner + 1, corner + 8, corner + 7)]
+            })
+            .collect();
+        let grid = Mesh::from_triangles(&grid_vertices, &grid_indices);
+        let indexed = IndexedMesh::from_mesh(&grid);
+        assert_eq!(indexed.vertices.len(), 49);
+        let world_to_clip = Matrix4::from(perspective(Deg(70.0), 1.0, 0.1, 10.0))
+            * Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -3.0});
+        let shaded = Cell::new(0);
+        let vertex_shader = |vertex: &Vertex3| {
+            shaded.set(shaded.get() + 1);
+            ClipVertex{position: world_to_clip * vertex.position.extend(1.0), varyings: vertex.normal}
+        };
+        let fragment_shader = |fragment: &Fragment<Vector3<f32>>| {
+            let n = fragment.varyings;
+            FloatColor::from_rgb(n.x.abs(), n.y.abs(), n.z.abs())
+        };
+
+        let mut soup = Renderer::new(Rasterizer::create(64, 64), MemoryTarget::new());
+        soup.draw_mesh(&grid, &vertex_shader, &fragment_shader);
+        assert_eq!(shaded.get(), 72 * 3);
+
+        shaded.set(0);
+        let mut indexed_renderer = Renderer::new(Rasterizer::create(64, 64), MemoryTarget::new());
+        indexed_renderer.draw_indexed_mesh(&indexed, &vertex_shader, &fragment_shader);
+        assert_eq!(shaded.get(), 49);
+
+        assert!(soup.rasterizer.get_color_buffer().cells().iter().any(|color| color.b > 0));
+        assert_eq!(
+            indexed_renderer.rasterizer.get_color_buffer().cells(),
+            soup.rasterizer.get_color_buffer().cells(),
+        );
+    }
+
+    #[test]
+    fn test_user_shaders_need_not_be_sync() {
+        // A fragment shader counting its calls in a `Cell`, which can't be shared between threads.
+        let shaded = Cell::new(0);
+        let vertex_shader = |vertex: &Vertex3| ClipVertex{position: vertex.position.extend(1.0), varyings: ()};
+        let fragment_shader = |_: &Fragment<()>| {
+            shaded.set(shaded.get() + 1);
+            FloatColor::from_rgb(1.0, 1.0, 1.0)
+        };
+        let mut renderer = Renderer::new(Rasterizer::create(16, 16), MemoryTarget::new());
+        renderer.draw_mesh(&Mesh::xy_face(2.0), &vertex_shader, &fragment_shader);
+        assert_eq!(shaded.get(), 16 * 16);
+    }
+
+    #[test]
+    fn test_custom_attributes_reach_fragments() {
+        // A triangle on the floor y = -1 whose near corner is behind the camera, so it gets
+        // clipped, with a per-vertex color that's an affine function of world z. Through the
+        // centre of pixel row r, the floor is at z = 1 / ndc_y, so that's the color expected there.
+        let vertex = |x: f32, z: f32| Vertex3{
+            position: Vector3{x, y: -1.0, z},
+            uv: Vector2{x: 0.0, y: 0.0},
+            normal: Vector3{x: 0.0, y: 1.0, z: 0.0},
+            attributes: FloatColor::from_rgb(0.0, (z + 10.0) / 12.0, 0.0),
+        };
+        let floor = Mesh{vertices: vec![(vertex(-4.0, -10.0), vertex(0.0, 2.0), vertex(4.0, -10.0))]};
+        let world_to_clip = Matrix4::from(perspective(Deg(90.0), 1.0, 0.1, 100.0));
+        let vertex_shader = |vertex: &Vertex3<FloatColor>| {
+            ClipVertex{position: world_to_clip * vertex.position.extend(1.0), varyings: vertex.attributes}
+        };
+        let column = std::sync::Mutex::new(vec![]);
+        let fragment_shader = |fragment: &Fragment<FloatColor>| {
+            if fragment.x == 16 {
+                column.lock().unwrap().push((fragment.y, fragment.varyings.g));
+            }
+            fragment.varyings
+        };
+        let mut renderer = Renderer::new(Rasterizer::create(32, 32), MemoryTarget::new());
+        renderer.draw_mesh(&floor, &vertex_shader, &fragment_shader);
+
+        let column = column.into_inner().unwrap();
+        assert!(column.len() >= 10, "only {} fragments in the column", column.len());
+        for &(row, g) in &column {
+            let ndc_y = 1.0 - (row as f32 + 0.5) / 16.0;
+            let expected = (1.0 / ndc_y + 10.0) / 12.0;
+            assert!((g - expected).abs() < 1e-3, "row {}: {} instead of {}", row, g, expected);
+        }
+    }
+
+    #[test]
+    fn test_model_matrix_matches_pretransformed_mesh() {
+        let transform = Matrix4::from_translation(Vector3{x: 0.2, y: -0.1, z: -3.0})
+            * Matrix4::from_nonuniform_scale(1.0, 2.0, 0.5)
+            * Matrix4::from_angle_y(Deg(30.0));
+        let mesh = Mesh::cube(1.0);
+        let mut placed = Renderer::new(Rasterizer::create(64, 64), MemoryTarget::new());
+        placed.mesh(&mesh, transform);
+        let mut baked = Renderer::new(Rasterizer::create(64, 64), MemoryTarget::new());
+        baked.mesh(&mesh.transformed(transform), Matrix4::identity());
+        let placed_colors = placed.rasterizer.get_color_buffer().cells();
+        let baked_colors = baked.rasterizer.get_color_buffer().cells();
+        assert!(placed_colors.iter().any(|color| color.r > 0));
+        // Allow for rounding differences between the two routes.
+        for (a, b) in placed_colors.iter().zip(baked_colors.iter()) {
+            assert!((a.r as i32 - b.r as i32).abs() <= 2);
+        }
+    }
+
+    #[test]
+    fn test_frustum_culling() {
+        let renderer = Renderer::new(Rasterizer::create(64, 64), MemoryTarget::new());
+        let frustum = renderer.frustum().unwrap();
+        let cube = Mesh::cube(1.0);
+        let Bounds{sphere, aabb: bounds} = cube.bounds().unwrap();
+        let at = |x, z| Matrix4::from_translation(Vector3{x, y: 0.0, z});
+        assert_eq!(frustum_relation(&frustum, sphere, bounds, at(0.0, -5.0)), Relation::In);
+        assert_eq!(frustum_relation(&frustum, sphere, bounds, at(0.0, 5.0)), Relation::Out);
+        assert_eq!(frustum_relation(&frustum, sphere, bounds, at(20.0, -5.0)), Relation::Out);
+        assert_eq!(frustum_relation(&frustum, sphere, bounds, at(0.0, 0.0)), Relation::Cross);
+        // Scaling grows the bounds along with the mesh.
+        let scaled = at(0.0, -5.0) * Matrix4::from_scale(20.0);
+        assert_eq!(frustum_relation(&frustum, sphere, bounds, scaled), Relation::Cross);
+    }
+
+    #[test]
+    fn test_supplied_bounds_are_trusted() {
+        let cube = Mesh::cube(1.0);
+        let bounds = cube.bounds().unwrap();
+        let transform = Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -5.0});
+        let mut renderer = Renderer::new(Rasterizer::create(16, 16), MemoryTarget::new());
+        // Bounds that put the cube behind the camera cull it without looking at its vertices.
+        let behind = Bounds{sphere: Sphere{center: Point3::new(0.0, 0.0, 10.0), ..bounds.sphere}, ..bounds};
+        renderer.mesh_with_bounds(&cube, Some(behind), transform);
+        assert!(renderer.rasterizer.get_color_buffer().cells().iter().all(|color| color.r == 0));
+        renderer.mesh_with_bounds(&cube, Some(bounds), transform);
+        assert!(renderer.rasterizer.get_color_buffer().cells().iter().any(|color| color.r > 0));
+        // Unknown bounds can't cull anything, so the cube is drawn, clipped, just the same.
+        assert_eq!(renderer.needs_clipping(None, transform), Some(true));
+        for &blend_mode in &[BlendMode::Opaque, BlendMode::Alpha] {
+            let mut renderer = Renderer::new(Rasterizer::create(16, 16), MemoryTarget::new());
+            renderer.set_blend_mode(blend_mode);
+            renderer.mesh_with_bounds(&cube, None, transform);
+            renderer.flush_transparent();
+            assert!(renderer.rasterizer.get_color_buffer().cells().iter().any(|color| color.r > 0));
+        }
+    }
+
+    #[test]
+    fn test_unclipped_fast_path_matches_clipping() {
+        let transform = Matrix4::from_translation(Vector3{x: 0.3, y: 0.0, z: -4.0}) * Matrix4::from_angle_y(Deg(30.0));
+        let mesh = Mesh::sphere(1.0, 3);
+        let mut fast = Renderer::new(Rasterizer::create(64, 64), MemoryTarget::new());
+        assert_eq!(fast.needs_clipping(mesh.bounds(), transform), Some(false));
+        fast.mesh(&mesh, transform);
+        let mut clipped = Renderer::new(Rasterizer::create(64, 64), MemoryTarget::new());
+        let vertex_shader = StandardVertexShader::with_model(clipped.world_to_clip_matrix(), transform);
+        let fragment_shader = PhongFragmentShader{
+            lighting: &clipped.lighting,
+            textures: &clipped.textures,
+            material: &clipped.material,
+            shadow_maps: &clipped.shadow_maps,
+            camera_position: clipped.camera_position,
+            model: clipped.specular_model,
+        };
+        draw_triangles(&mut clipped.rasterizer, &mesh.vertices, &vertex_shader, &Parallel(&fragment_shader), true, PolygonMode::Fill);
+        assert!(fast.rasterizer.get_color_buffer().cells().iter().any(|color| color.r > 0));
+        assert_eq!(fast.rasterizer.get_color_buffer().cells(), clipped.rasterizer.get_color_buffer().cells());
+    }
+
+    #[test]
+    fn test_render_to_texture() {
+        let mut renderer = Renderer::new(Rasterizer::create(16, 16), MemoryTarget::new());
+        renderer.lighting.lights.clear();
+        renderer.lighting.ambient = FloatColor::from_rgb(1.0, 1.0, 1.0);
+        let unlit = |ambient: FloatColor, texture: Option<usize>| Material{
+            ambient,
+            specular: FloatColor::from_rgb(0.0, 0.0, 0.0),
+            texture,
+            ..Material::new()
+        };
+        let mut target = RenderTarget::new(8, 8);
+        // A face filling the top half of the view, seen from a camera that's been moved back.
+        renderer.render_to(&mut target, |renderer| {
+            renderer.set_from_camera(&Camera::create(70.0, 1.0, 0.1, 100.0, Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: 1.0})));
+            renderer.set_material(unlit(FloatColor::from_rgb(1.0, 0.0, 0.0), None));
+            renderer.mesh(&Mesh::xy_face(10.0), Matrix4::from_translation(Vector3{x: 0.0, y: 5.0, z: -1.0}));
+        });
+        assert_eq!(renderer.world_to_view_matrix, Matrix4::identity());
+        assert!(renderer.rasterizer.get_color_buffer().cells().iter().all(|color| color.r == 0));
+        assert_eq!((target.width(), target.height()), (8, 8));
+        assert!((0..8).all(|x| target.color().at(x, 2).unwrap().r == 255 && target.color().at(x, 5).unwrap().r == 0));
+        assert!(target.depth().at(4, 2).unwrap() < 1.0);
+
+        let texture = target.to_texture();
+        assert_eq!(texture.sample(0.5, 0.25, TextureFilterMode::NearestNeighbor).r, 255);
+        assert_eq!(texture.sample(0.5, 0.75, TextureFilterMode::NearestNeighbor).r, 0);
+        renderer.set_texture(0, texture);
+        renderer.set_material(unlit(FloatColor::from_rgb(1.0, 1.0, 1.0), Some(0)));
+        renderer.mesh(&Mesh::xy_face(10.0), Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -1.0}));
+        let colors = renderer.rasterizer.get_color_buffer();
+        assert!(colors.cells().iter().any(|color| color.r == 255) && colors.cells().iter().any(|color| color.r == 0));
+
+        // Each pass starts from a clear target.
+        renderer.render_to(&mut target, |_| {});
+        assert!(target.color().cells().iter().all(|color| color.r == 0));
+    }
+
+    #[test]
+    fn test_present_to_memory_target() {
+        let mut renderer = Renderer::new(Rasterizer::create(8, 8), MemoryTarget::new());
+        renderer.lighting.lights.clear();
+        renderer.lighting.ambient = FloatColor::from_rgb(1.0, 1.0, 1.0);
+        renderer.set_from_camera(&Camera::create(70.0, 1.0, 0.1, 100.0, Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: 1.0})));
+        renderer.set_material(Material{
+            ambient: FloatColor::from_rgb(1.0, 0.0, 0.0),
+            specular: FloatColor::from_rgb(0.0, 0.0, 0.0),
+            ..Material::new()
+        });
+        // The top half of the view is red.
+        renderer.mesh(&Mesh::xy_face(10.0), Matrix4::from_translation(Vector3{x: 0.0, y: 5.0, z: -1.0}));
+        assert!(renderer.output.frame().is_none());
+        renderer.present();
+
+        let frame = renderer.output.take_frame().unwrap();
+        assert_eq!((frame.width(), frame.height()), (8, 8));
+        assert!((0..8).all(|x| frame.at(x, 2).unwrap().r == 255 && frame.at(x, 5).unwrap().r == 0));
+        assert!(renderer.output.take_frame().is_none());
+        // The renderer starts the next frame from a clear buffer.
+        assert!(renderer.rasterizer.get_color_buffer().cells().iter().all(|color| color.r == 0));
+    }
+
+    #[test]
+    fn test_transparent_draws_blend_back_to_front() {
+        let mut renderer = Renderer::new(Rasterizer::create(16, 16), MemoryTarget::new());
+        // Unlit, so each face is exactly its ambient color.
+        renderer.lighting.lights.clear();
+        renderer.lighting.ambient = FloatColor::from_rgb(1.0, 1.0, 1.0);
+        let face = Mesh::xy_face(1.0);
+        let tinted = |r: f32, b: f32| Material{
+            ambient: FloatColor::from_rgb(r, 0.0, b),
+            specular: FloatColor::from_rgb(0.0, 0.0, 0.0),
+            opacity: 0.5,
+            ..Material::new()
+        };
+        renderer.set_blend_mode(BlendMode::Alpha);
+        renderer.set_depth_write(false);
+        // The near face is drawn first, but has to end up on top.
+        renderer.set_material(tinted(1.0, 0.0));
+        renderer.mesh(&face, Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -2.0}));
+        renderer.set_material(tinted(0.0, 1.0));
+        renderer.mesh(&face, Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -3.0}));
+        assert!(renderer.rasterizer.get_color_buffer().cells().iter().all(|color| color.r == 0 && color.b == 0));
+        renderer.flush_transparent();
+        let center = renderer.rasterizer.get_color_buffer().at(8, 8).unwrap();
+        assert_eq!((center.r, center.b), (127, 63));
+        assert_eq!(renderer.rasterizer.blend_mode(), BlendMode::Alpha);
+        assert!(renderer.rasterizer.get_z_buffer().cells().iter().all(|&z| z == std::f32::MAX));
+    }
+
+    #[test]
+    fn test_transparent_draws_with_nan_depths() {
+        let mut renderer = Renderer::new(Rasterizer::create(16, 16), MemoryTarget::new());
+        renderer.set_blend_mode(BlendMode::Alpha);
+        let face = Mesh::xy_face(1.0);
+        let bounds = face.bounds();
+        let mut broken = face.clone();
+        broken.vertices[0].0.position.x = std::f32::NAN;
+        let nan_transform = Matrix4::from_translation(Vector3{x: std::f32::NAN, y: 0.0, z: -2.0});
+        for z in 2..5 {
+            renderer.mesh_with_bounds(&face, bounds, Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -z as f32}));
+            renderer.mesh_with_bounds(&broken, bounds, Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -z as f32}));
+            renderer.mesh_with_bounds(&face, bounds, nan_transform);
+        }
+        assert_eq!(renderer.transparent_draws.len(), 9);
+        renderer.flush_transparent();
+        assert!(renderer.transparent_draws.is_empty());
+    }
+
+    #[test]
+    fn test_polygon_modes() {
+        // A face filling the middle of the screen, with its diagonal running corner to corner.
+        let draw = |polygon_mode: PolygonMode| {
+            let mut renderer = Renderer::new(Rasterizer::create(32, 32), MemoryTarget::new());
+            renderer.lighting.lights.clear();
+            renderer.lighting.ambient = FloatColor::from_rgb(1.0, 1.0, 1.0);
+            renderer.set_material(Material{ambient: FloatColor::from_rgb(0.0, 1.0, 0.0), ..Material::new()});
+            renderer.set_polygon_mode(polygon_mode);
+            renderer.mesh(&Mesh::xy_face(2.0), Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -2.0}));
+            renderer.rasterizer.get_color_buffer().clone()
+        };
+        let lit = |frame: &Frame<Color>| frame.cells().iter().filter(|color| color.g > 0).count();
+        let filled = draw(PolygonMode::Fill);
+        let wireframe = draw(PolygonMode::Line);
+        let points = draw(PolygonMode::Point);
+        assert!(lit(&wireframe) > 0 && lit(&wireframe) < lit(&filled) / 4);
+        assert_eq!(lit(&points), 4);
+        // The middle of each half of the face is empty.
+        assert_eq!(wireframe.at(12, 20).unwrap().g, 0);
+        assert_eq!(wireframe.at(20, 12).unwrap().g, 0);
+
+        let red = FloatColor::from_rgb(1.0, 0.0, 0.0);
+        let overlay = draw(PolygonMode::FillAndLine{color: red, depth_bias: -0.0001});
+        for (index, color) in overlay.cells().iter().enumerate() {
+            if wireframe.cells()[index].g > 0 {
+                assert_eq!((color.r, color.g), (255, 0));
+            } else {
+                assert_eq!(*color, filled.cells()[index]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_polygon_modes_under_supersampling() {
+        // Wireframes and points cover about as much of the screen whether or not they're drawn
+        // supersampled, rather than fading to a fraction of a pixel.
+        let brightness = |antialiasing: Antialiasing, polygon_mode: PolygonMode| {
+            let rasterizer = Rasterizer::with_antialiasing(32, 32, antialiasing);
+            let mut renderer = Renderer::new(rasterizer, MemoryTarget::new());
+            renderer.lighting.lights.clear();
+            renderer.lighting.ambient = FloatColor::from_rgb(1.0, 1.0, 1.0);
+            renderer.set_material(Material{ambient: FloatColor::from_rgb(0.0, 1.0, 0.0), ..Material::new()});
+            renderer.set_polygon_mode(polygon_mode);
+            renderer.mesh(&Mesh::xy_face(2.0), Matrix4::from_translation(Vector3{x: 0.0, y: 0.0, z: -2.0}));
+            renderer.rasterizer.resolve();
+            renderer.rasterizer.get_color_buffer().cells().iter().map(|color| color.g as f32).sum::<f32>()
+        };
+        for &polygon_mode in &[PolygonMode::Line, PolygonMode::Point] {
+            let ratio = brightness(Antialiasing::Supersample(2), polygon_mode) / brightness(Antialiasing::None, polygon_mode);
+            assert!(ratio > 0.8 && ratio < 1.25, "{:?} drew {} times as bright supersampled", polygon_mode, ratio);
+        }
+    }
+
+    #[test]
+    fn test_debug_lines_and_points() {
+        let mut renderer = Renderer::new(Rasterizer::create(32, 32), MemoryTarget::new());
+        let red = FloatColor::from_rgb(1.0, 0.0, 0.0);
+        // A polyline running off the side of the screen is clipped rather than dropped.
+        renderer.polyline(
+            &[Vector3{x: -1.0, y: 0.0, z: -2.0}, Vector3{x: 0.0, y: 0.0, z: -2.0}, Vector3{x: 50.0, y: 0.0, z: -2.0}],
+            red,
+            2.0,
+        );
+        let colors = renderer.rasterizer.get_color_buffer();
+        assert!((5..32).all(|x| colors.at(x, 15).unwrap().r == 255 && colors.at(x, 16).unwrap().r == 255));
+        assert!((0..32).all(|x| colors.at(x, 13).unwrap().r == 0 && colors.at(x, 18).unwrap().r == 0));
+        assert_eq!(colors.at(2, 15).unwrap().r, 0);
+
+        // Points are depth tested against the line and skipped outside the view.
+        let green = FloatColor::from_rgb(0.0, 1.0, 0.0);
+        renderer.point(Vector3{x: 0.0, y: 0.0, z: -3.0}, green, 5.0);
+        renderer.point(Vector3{x: 0.0, y: 0.0, z: 3.0}, green, 5.0);
+        renderer.point(Vector3{x: 0.0, y: 0.5, z: -2.0}, green, 5.0);
+        let colors = renderer.rasterizer.get_color_buffer();
+        assert_eq!(colors.at(15, 10).unwrap().g, 255);
+        // The point behind the line only shows above and below it.
+        assert_eq!(colors.at(15, 14).unwrap().g, 255);
+        assert!((8..24).all(|x| colors.at(x, 15).unwrap().g == 0 && colors.at(x, 16).unwrap().g == 0));
+        // Nothing from the point behind the camera.
+        assert!((0..32).all(|x| colors.at(x, 22).unwrap().g == 0 && colors.at(x, 5).unwrap().g == 0));
+    }
+
+    #[test]
+    fn test_normal_matrix_handles_nonuniform_scale() {
+        // A 45 degree slope squashed vertically becomes shallower, so its normal steepens.
+        let scale = Matrix4::from_nonuniform_scale(1.0, 0.5, 1.0);
+        let normal = (normal_matrix(scale) * Vector3{x: -1.0, y: 1.0, z: 0.0}).normalize();
+        let surface = (scale * Vector4{x: 1.0, y: 1.0, z: 0.0, w: 0.0}).truncate();
+        assert!(normal.dot(surface).abs() < 1e-6);
+    }
 }
@@ -1,8 +1,9 @@
+use colors::Color;
 use frame::Frame;
+use output::OutputTarget;
 use sdl2;
 use sdl2::*;
 use sdl2::{
-    pixels::Color,
     render::Canvas,
     video::Window,
 };
@@ -57,3 +58,8 @@ pub fn render_to_canvas(
     }
 }
 
+impl OutputTarget for Canvas<Window> {
+    fn present(&mut self, color_buffer: &Frame<Color>) {
+        render_to_canvas(self, color_buffer);
+    }
+}
@@ -0,0 +1,450 @@
+use cgmath::*;
+use colors::*;
+use geometry::*;
+use light::*;
+use materials::*;
+use math::Homogenous;
+use shadows::ShadowMap;
+use std::collections::HashMap;
+use textures::*;
+
+/// Values that are produced per vertex by a vertex shader and interpolated across the surface
+/// of a triangle before being handed to a fragment shader.
+pub trait Varying: Copy {
+    fn scaled(&self, factor: f32) -> Self;
+
+    fn added(&self, other: &Self) -> Self;
+
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self.scaled(1.0 - t).added(&other.scaled(t))
+    }
+
+    fn weighted_sum(values: (&Self, &Self, &Self), weights: (f32, f32, f32)) -> Self {
+        values.0.scaled(weights.0)
+            .added(&values.1.scaled(weights.1))
+            .added(&values.2.scaled(weights.2))
+    }
+}
+
+impl Varying for () {
+    fn scaled(&self, _factor: f32) -> Self {}
+
+    fn added(&self, _other: &Self) -> Self {}
+}
+
+impl Varying for f32 {
+    fn scaled(&self, factor: f32) -> Self {
+        self * factor
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        self + other
+    }
+}
+
+impl Varying for Vector2<f32> {
+    fn scaled(&self, factor: f32) -> Self {
+        self * factor
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        self + other
+    }
+}
+
+impl Varying for Vector3<f32> {
+    fn scaled(&self, factor: f32) -> Self {
+        self * factor
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        self + other
+    }
+}
+
+impl Varying for Vector4<f32> {
+    fn scaled(&self, factor: f32) -> Self {
+        self * factor
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        self + other
+    }
+}
+
+impl Varying for FloatColor {
+    fn scaled(&self, factor: f32) -> Self {
+        *self * factor
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        *self + *other
+    }
+}
+
+// Fixed-size payloads of plain floats, e.g. bone weights.
+impl <const N: usize> Varying for [f32; N] {
+    fn scaled(&self, factor: f32) -> Self {
+        let mut result = *self;
+        result.iter_mut().for_each(|x| *x *= factor);
+        result
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        let mut result = *self;
+        result.iter_mut().zip(other.iter()).for_each(|(x, y)| *x += y);
+        result
+    }
+}
+
+impl <A: Varying, B: Varying> Varying for (A, B) {
+    fn scaled(&self, factor: f32) -> Self {
+        (self.0.scaled(factor), self.1.scaled(factor))
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        (self.0.added(&other.0), self.1.added(&other.1))
+    }
+}
+
+impl <A: Varying, B: Varying, C: Varying> Varying for (A, B, C) {
+    fn scaled(&self, factor: f32) -> Self {
+        (self.0.scaled(factor), self.1.scaled(factor), self.2.scaled(factor))
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        (self.0.added(&other.0), self.1.added(&other.1), self.2.added(&other.2))
+    }
+}
+
+/// Output of a vertex shader: a position in homogenous clip space plus the varyings that the
+/// rasterizer will interpolate for each fragment.
+#[derive(Clone, Copy, Debug)]
+pub struct ClipVertex<V> {
+    pub position: Vector4<f32>,
+    pub varyings: V,
+}
+
+impl <V: Varying> Homogenous<f32> for ClipVertex<V> {
+    fn homogenous_position(&self) -> Vector4<f32> {
+        self.position
+    }
+
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        ClipVertex{
+            position: self.position * (1.0 - t) + other.position * t,
+            varyings: self.varyings.lerp(&other.varyings, t),
+        }
+    }
+}
+
+/// Everything a fragment shader knows about the pixel it is shading.
+#[derive(Clone, Copy, Debug)]
+pub struct Fragment<V> {
+    pub x: usize,
+    pub y: usize,
+    pub depth: f32,
+    pub varyings: V,
+    /// How much the varyings change for a one pixel step in x and in y, e.g. for picking a mip
+    /// level.
+    pub ddx: V,
+    pub ddy: V,
+    /// Whether the fragment is on the front face of its triangle, which is only ever false when
+    /// back faces aren't culled.
+    pub front_facing: bool,
+}
+
+pub trait VertexShader<I> {
+    type Varyings: Varying;
+
+    fn shade(&self, vertex: &I) -> ClipVertex<Self::Varyings>;
+}
+
+pub trait FragmentShader<V> {
+    fn shade(&self, fragment: &Fragment<V>) -> FloatColor;
+}
+
+impl <I, V, F> VertexShader<I> for F where V: Varying, F: Fn(&I) -> ClipVertex<V> {
+    type Varyings = V;
+
+    fn shade(&self, vertex: &I) -> ClipVertex<V> {
+        self(vertex)
+    }
+}
+
+impl <V, F> FragmentShader<V> for F where F: Fn(&Fragment<V>) -> FloatColor {
+    fn shade(&self, fragment: &Fragment<V>) -> FloatColor {
+        self(fragment)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StandardVaryings<A = ()> {
+    pub world_position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+    pub attributes: A,
+}
+
+impl <A: Varying> Varying for StandardVaryings<A> {
+    fn scaled(&self, factor: f32) -> Self {
+        StandardVaryings{
+            world_position: self.world_position * factor,
+            normal: self.normal * factor,
+            uv: self.uv * factor,
+            attributes: self.attributes.scaled(factor),
+        }
+    }
+
+    fn added(&self, other: &Self) -> Self {
+        StandardVaryings{
+            world_position: self.world_position + other.world_position,
+            normal: self.normal + other.normal,
+            uv: self.uv + other.uv,
+            attributes: self.attributes.added(&other.attributes),
+        }
+    }
+}
+
+/// Transforms object-space vertices into world space and then clip space, passing world
+/// position, normal, uv and any vertex attributes on to the fragment shader.
+pub struct StandardVertexShader {
+    pub world_to_clip: Matrix4<f32>,
+    pub object_to_world: Matrix4<f32>,
+    // Normals need the inverse transpose of the object-to-world transform, so that they stay
+    // perpendicular to surfaces under non-uniform scaling.
+    normal_matrix: Matrix3<f32>,
+}
+
+impl StandardVertexShader {
+    /// A shader for vertices that are already in world space.
+    pub fn new(world_to_clip: Matrix4<f32>) -> Self {
+        Self::with_model(world_to_clip, Matrix4::identity())
+    }
+
+    pub fn with_model(world_to_clip: Matrix4<f32>, object_to_world: Matrix4<f32>) -> Self {
+        StandardVertexShader{
+            world_to_clip,
+            object_to_world,
+            normal_matrix: normal_matrix(object_to_world),
+        }
+    }
+}
+
+/// The inverse transpose of the upper 3x3 of `transformation`. Singular transformations (such as
+/// a scale of zero) fall back to the upper 3x3 itself.
+pub fn normal_matrix(transformation: Matrix4<f32>) -> Matrix3<f32> {
+    let linear = Matrix3::from_cols(
+        transformation.x.truncate(),
+        transformation.y.truncate(),
+        transformation.z.truncate(),
+    );
+    linear.invert().map(|inverse| inverse.transpose()).unwrap_or(linear)
+}
+
+impl <A: Varying> VertexShader<Vertex3<A>> for StandardVertexShader {
+    type Varyings = StandardVaryings<A>;
+
+    fn shade(&self, vertex: &Vertex3<A>) -> ClipVertex<StandardVaryings<A>> {
+        let world_position = (self.object_to_world * vertex.position.extend(1.0)).truncate();
+        ClipVertex{
+            position: self.world_to_clip * world_position.extend(1.0),
+            varyings: StandardVaryings{
+                world_position,
+                normal: self.normal_matrix * vertex.normal,
+                uv: vertex.uv,
+                attributes: vertex.attributes,
+            },
+        }
+    }
+}
+
+/// Diffuse lighting from the renderer's lights, modulated by the material's texture.
+pub struct LambertFragmentShader<'a> {
+    pub lighting: &'a Lighting,
+    pub textures: &'a HashMap<usize, Texture>,
+    pub material: &'a Material,
+    /// Shadow maps parallel to `lighting.lights`; lights without one are unoccluded.
+    pub shadow_maps: &'a [Option<ShadowMap>],
+}
+
+impl <'a, A> FragmentShader<StandardVaryings<A>> for LambertFragmentShader<'a> {
+    fn shade(&self, fragment: &Fragment<StandardVaryings<A>>) -> FloatColor {
+        let varyings = &fragment.varyings;
+        let normal = facing_normal(fragment);
+        let texture_color = sample_material_texture(fragment, self.textures, self.material);
+        let color_from_lights = self.lighting.lights.iter()
+            .enumerate()
+            .map(|(index, light)| {
+                lambert_light(
+                    &varyings.world_position,
+                    &normal,
+                    light,
+                    self.material,
+                ) * shadow_visibility(self.shadow_maps, index, light, &varyings.world_position, &normal)
+            })
+            .sum();
+        let ambient_color = FloatColor::multiply_colors(&self.lighting.ambient, &self.material.ambient);
+        let total_light_color = ambient_color + color_from_lights;
+
+        let net_color = FloatColor::multiply_colors(&texture_color, &total_light_color);
+        FloatColor{a: texture_color.a * self.material.opacity, ..net_color}.clamped()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpecularModel {
+    /// Reflects the light about the normal and compares it with the view direction.
+    Phong,
+    /// Compares the normal with the half vector between light and view directions, which is
+    /// cheaper and keeps highlights from cutting off at grazing angles.
+    BlinnPhong,
+}
+
+/// Diffuse lighting plus specular highlights from the material's `specular` color and
+/// `shininess`. The texture only modulates the diffuse and ambient terms.
+pub struct PhongFragmentShader<'a> {
+    pub lighting: &'a Lighting,
+    pub textures: &'a HashMap<usize, Texture>,
+    pub material: &'a Material,
+    pub shadow_maps: &'a [Option<ShadowMap>],
+    pub camera_position: Vector3<f32>,
+    pub model: SpecularModel,
+}
+
+impl <'a, A> FragmentShader<StandardVaryings<A>> for PhongFragmentShader<'a> {
+    fn shade(&self, fragment: &Fragment<StandardVaryings<A>>) -> FloatColor {
+        let varyings = &fragment.varyings;
+        let normal = facing_normal(fragment);
+        let view = (self.camera_position - varyings.world_position).normalize();
+        let texture_color = sample_material_texture(fragment, self.textures, self.material);
+        let mut diffuse = FloatColor::multiply_colors(&self.lighting.ambient, &self.material.ambient);
+        let mut specular = FloatColor::from_rgb(0.0, 0.0, 0.0);
+        for (index, light) in self.lighting.lights.iter().enumerate() {
+            let visibility = shadow_visibility(self.shadow_maps, index, light, &varyings.world_position, &normal);
+            if visibility == 0.0 {
+                continue;
+            }
+            diffuse = diffuse
+                + lambert_light(&varyings.world_position, &normal, light, self.material) * visibility;
+            specular = specular + specular_light(
+                &varyings.world_position,
+                &normal,
+                &view,
+                light,
+                self.material,
+                self.model,
+            ) * visibility;
+        }
+        let color = FloatColor::multiply_colors(&texture_color, &diffuse) + specular;
+        FloatColor{a: texture_color.a * self.material.opacity, ..color}.clamped()
+    }
+}
+
+// Back faces are lit as though the surface were two-sided.
+fn facing_normal<A>(fragment: &Fragment<StandardVaryings<A>>) -> Vector3<f32> {
+    let normal = fragment.varyings.normal.normalize();
+    if fragment.front_facing { normal } else { -normal }
+}
+
+fn sample_material_texture<A>(
+    fragment: &Fragment<StandardVaryings<A>>,
+    textures: &HashMap<usize, Texture>,
+    material: &Material,
+) -> FloatColor {
+    let texture = material.texture
+        .and_then(|index| textures.get(&index));
+    match texture {
+        Some(ref t) => {
+            FloatColor::from_color(&t.sample_with_derivatives(
+                fragment.varyings.uv,
+                fragment.ddx.uv,
+                fragment.ddy.uv,
+                TextureFilterMode::Trilinear,
+            ))
+        },
+        None => FloatColor::from_rgb(1.0, 1.0, 1.0),
+    }
+}
+
+fn shadow_visibility(
+    shadow_maps: &[Option<ShadowMap>],
+    index: usize,
+    light: &Light,
+    world_position: &Vector3<f32>,
+    normal: &Vector3<f32>,
+) -> f32 {
+    match shadow_maps.get(index) {
+        Some(&Some(ref shadow_map)) => {
+            shadow_map.visibility(world_position, normal, &light.incidence(world_position).0)
+        },
+        _ => 1.0,
+    }
+}
+
+fn lambert_light(
+    world_position: &Vector3<f32>,
+    normal: &Vector3<f32>,
+    light: &Light,
+    material: &Material,
+) -> FloatColor {
+    let (direction, attenuation) = light.incidence(world_position);
+    let intensity = direction.dot(*normal) * attenuation;
+    let intensity = if intensity < 0.0 { 0.0 } else { intensity };
+    FloatColor::multiply_colors(&material.diffuse, &light.color) * intensity
+}
+
+fn specular_light(
+    world_position: &Vector3<f32>,
+    normal: &Vector3<f32>,
+    view: &Vector3<f32>,
+    light: &Light,
+    material: &Material,
+    model: SpecularModel,
+) -> FloatColor {
+    let (direction, attenuation) = light.incidence(world_position);
+    // No highlights on the side facing away from the light.
+    if direction.dot(*normal) <= 0.0 {
+        return FloatColor::from_rgb(0.0, 0.0, 0.0);
+    }
+    let alignment = match model {
+        SpecularModel::Phong => {
+            let reflected = *normal * (2.0 * direction.dot(*normal)) - direction;
+            reflected.dot(*view)
+        },
+        SpecularModel::BlinnPhong => {
+            let half = direction + view;
+            if half.magnitude2() == 0.0 { 0.0 } else { half.normalize().dot(*normal) }
+        },
+    };
+    if alignment <= 0.0 {
+        return FloatColor::from_rgb(0.0, 0.0, 0.0);
+    }
+    let intensity = alignment.powf(material.shininess) * attenuation;
+    FloatColor::multiply_colors(&material.specular, &light.color) * intensity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_specular_models_peak_at_mirror_direction() {
+        let mut material = Material::new();
+        material.shininess = 16.0;
+        let light = Light::directional_light(Vector3{x: 1.0, y: 1.0, z: 0.0});
+        let normal = Vector3{x: 0.0, y: 1.0, z: 0.0};
+        let position = Vector3{x: 0.0, y: 0.0, z: 0.0};
+        let mirror = Vector3{x: -1.0, y: 1.0, z: 0.0}.normalize();
+        let off_axis = Vector3{x: 0.0, y: 1.0, z: 1.0}.normalize();
+        for &model in &[SpecularModel::Phong, SpecularModel::BlinnPhong] {
+            let peak = specular_light(&position, &normal, &mirror, &light, &material, model);
+            let off = specular_light(&position, &normal, &off_axis, &light, &material, model);
+            assert!((peak.r - 1.0).abs() < 1e-5);
+            assert!(off.r < 0.5);
+        }
+        let below = Light::directional_light(Vector3{x: 1.0, y: -1.0, z: 0.0});
+        let dark = specular_light(&position, &normal, &mirror, &below, &material, SpecularModel::Phong);
+        assert_eq!(dark.r, 0.0);
+    }
+}
@@ -0,0 +1,291 @@
+use camera::Projection;
+use cgmath::*;
+use colors::*;
+use frame::Frame;
+use geometry::*;
+use light::*;
+use math::*;
+use rasterizer::*;
+use render::{draw_triangles, Parallel, PolygonMode};
+use shaders::*;
+use std::sync::Arc;
+use thread_pool::ThreadPool;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    /// Width and height of each depth map, in texels.
+    pub resolution: usize,
+    /// How far, in world units, surfaces are pushed towards the light before the depth
+    /// comparison. Too little gives shadow acne, too much detaches shadows from their casters.
+    pub bias: f32,
+    /// How far surfaces are pushed along their normal, in shadow map texels. This is what
+    /// keeps surfaces at grazing angles to the light from shadowing themselves, and should grow
+    /// with `pcf_radius`.
+    pub normal_bias: f32,
+    /// Texels on each side of the lookup averaged for percentage-closer filtering; 0 gives hard
+    /// shadows.
+    pub pcf_radius: i32,
+}
+
+impl ShadowSettings {
+    pub fn new() -> Self {
+        ShadowSettings{
+            resolution: 512,
+            bias: 0.01,
+            normal_bias: 2.0,
+            pcf_radius: 1,
+        }
+    }
+}
+
+// One depth map along with the transform that produced it.
+#[derive(Clone, Debug)]
+struct ShadowFace {
+    light_to_clip: Matrix4<f32>,
+    // World-space size of a texel per unit of clip-space w.
+    texel_size: f32,
+    depth: Frame<f32>,
+}
+
+/// Depth as seen from a light: a single map for directional and spot lights, or a cube of six
+/// for point lights.
+#[derive(Clone, Debug)]
+pub struct ShadowMap {
+    faces: Vec<ShadowFace>,
+    settings: ShadowSettings,
+}
+
+impl ShadowMap {
+    /// Renders the depth of `casters`, each with its object-to-world transform, from `light`.
+    /// Returns None if there is nothing to cast a shadow.
+    pub fn render<A: Copy>(
+        light: &Light,
+        casters: &[(&Mesh<A>, Matrix4<f32>)],
+        settings: ShadowSettings,
+    ) -> Option<Self> {
+        Self::render_on(light, casters, settings, None)
+    }
+
+    // Like `render`, but on the worker threads of `pool` if there is one, rather than starting
+    // some for every map.
+    pub(crate) fn render_on<A: Copy>(
+        light: &Light,
+        casters: &[(&Mesh<A>, Matrix4<f32>)],
+        settings: ShadowSettings,
+        pool: Option<Arc<ThreadPool>>,
+    ) -> Option<Self> {
+        let positions: Vec<Vector3<f32>> = casters.iter()
+            .flat_map(|&(mesh, object_to_world)| mesh.vertices.iter()
+                .flat_map(|&(v0, v1, v2)| vec![v0.position, v1.position, v2.position])
+                .map(move |position| (object_to_world * position.extend(1.0)).truncate()))
+            .collect();
+        if positions.is_empty() {
+            return None;
+        }
+        let transforms = match light.light_type {
+            LightType::Directional(ref directional_light) => {
+                let (center, radius) = bounding_sphere(&positions);
+                let eye = Point3::from_vec(center + directional_light.direction * radius * 2.0);
+                let view = Matrix4::look_at_dir(eye, -directional_light.direction, up_for(directional_light.direction));
+                let projection = Projection::Orthographic{
+                    width: radius * 2.0,
+                    height: radius * 2.0,
+                    near: 0.0,
+                    far: radius * 4.0,
+                };
+                vec![(projection.matrix() * view, radius * 2.0)]
+            },
+            LightType::Spot(ref spot_light) => {
+                let far = farthest_distance(spot_light.position, &positions);
+                let fov = Deg::from(spot_light.outer_angle * 2.0).0.min(170.0);
+                let projection = Projection::Perspective{fov, aspect: 1.0, near: far * 0.001, far}.matrix();
+                let eye = Point3::from_vec(spot_light.position);
+                let view = Matrix4::look_at_dir(eye, spot_light.direction, up_for(spot_light.direction));
+                vec![(projection * view, Deg(fov / 2.0).tan() * 2.0)]
+            },
+            LightType::Point(ref point_light) => {
+                let far = farthest_distance(point_light.position, &positions);
+                let projection = Projection::Perspective{fov: 90.0, aspect: 1.0, near: far * 0.001, far}.matrix();
+                let eye = Point3::from_vec(point_light.position);
+                CUBE_DIRECTIONS.iter()
+                    .map(|&direction| (projection * Matrix4::look_at_dir(eye, direction, up_for(direction)), 2.0))
+                    .collect()
+            },
+        };
+        let mut rasterizer = Rasterizer::create(settings.resolution as u32, settings.resolution as u32);
+        if let Some(pool) = pool {
+            rasterizer.share_pool(pool);
+        }
+        let faces = transforms.into_iter()
+            .map(|(light_to_clip, extent)| {
+                rasterizer.clear();
+                let fragment_shader = |_: &Fragment<()>| FloatColor::from_rgb(0.0, 0.0, 0.0);
+                for &(mesh, object_to_world) in casters {
+                    let object_to_clip = light_to_clip * object_to_world;
+                    let vertex_shader = |vertex: &Vertex3<A>| ClipVertex{
+                        position: object_to_clip * vertex.position.extend(1.0),
+                        varyings: (),
+                    };
+                    draw_triangles(&mut rasterizer, &mesh.vertices, &vertex_shader, &Parallel(&fragment_shader), true, PolygonMode::Fill);
+                }
+                ShadowFace{
+                    light_to_clip,
+                    texel_size: extent / settings.resolution as f32,
+                    depth: rasterizer.get_z_buffer().clone(),
+                }
+            })
+            .collect();
+        Some(ShadowMap{faces, settings})
+    }
+
+    /// The fraction of light reaching `world_position`, from 0 (fully shadowed) to 1.
+    /// `normal` is the surface's unit normal and `to_light` the unit direction from the point
+    /// towards the light.
+    pub fn visibility(
+        &self,
+        world_position: &Vector3<f32>,
+        normal: &Vector3<f32>,
+        to_light: &Vector3<f32>,
+    ) -> f32 {
+        let face = if self.faces.len() == 6 {
+            &self.faces[cube_face(-*to_light)]
+        } else {
+            &self.faces[0]
+        };
+        let w = (face.light_to_clip * world_position.extend(1.0)).w;
+        let texel = face.texel_size * w.abs();
+        let biased = world_position
+            + to_light * self.settings.bias
+            + normal * (self.settings.normal_bias * texel);
+        let clip = face.light_to_clip * biased.extend(1.0);
+        if clip.w <= 0.0 {
+            return 1.0;
+        }
+        let ndc = from_homogenous(clip);
+        // Anything past the far plane is behind every caster in the map.
+        let depth = ndc.z.min(1.0);
+        // Same mapping as the rasterizer, where each texel covers the square around its centre.
+        let size = self.settings.resolution as f32;
+        let x = (((ndc.x + 1.0) / 2.0) * size).floor() as i32;
+        let y = (((1.0 - ndc.y) / 2.0) * size).floor() as i32;
+        let last = self.settings.resolution as i32 - 1;
+        if x < 0 || y < 0 || x > last || y > last {
+            return 1.0;
+        }
+        let radius = self.settings.pcf_radius;
+        let mut lit = 0;
+        let mut total = 0;
+        for dy in -radius..radius + 1 {
+            for dx in -radius..radius + 1 {
+                let sx = (x + dx).max(0).min(last) as usize;
+                let sy = (y + dy).max(0).min(last) as usize;
+                if depth <= face.depth.at(sx, sy).unwrap() {
+                    lit += 1;
+                }
+                total += 1;
+            }
+        }
+        lit as f32 / total as f32
+    }
+}
+
+const CUBE_DIRECTIONS: [Vector3<f32>; 6] = [
+    Vector3{x: 1.0, y: 0.0, z: 0.0},
+    Vector3{x: -1.0, y: 0.0, z: 0.0},
+    Vector3{x: 0.0, y: 1.0, z: 0.0},
+    Vector3{x: 0.0, y: -1.0, z: 0.0},
+    Vector3{x: 0.0, y: 0.0, z: 1.0},
+    Vector3{x: 0.0, y: 0.0, z: -1.0},
+];
+
+// The index in CUBE_DIRECTIONS of the face along the major axis of `away`, the direction from
+// the light to a point. Comparisons with NaN are false, so a NaN direction still gets a face.
+fn cube_face(away: Vector3<f32>) -> usize {
+    let (x, y, z) = (away.x.abs(), away.y.abs(), away.z.abs());
+    if x >= y && x >= z {
+        if away.x >= 0.0 { 0 } else { 1 }
+    } else if y >= z {
+        if away.y >= 0.0 { 2 } else { 3 }
+    } else if away.z >= 0.0 {
+        4
+    } else {
+        5
+    }
+}
+
+// Any up vector will do as long as it isn't parallel to the view direction.
+fn up_for(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3{x: 0.0, y: 0.0, z: 1.0}
+    } else {
+        Vector3{x: 0.0, y: 1.0, z: 0.0}
+    }
+}
+
+fn bounding_sphere(positions: &Vec<Vector3<f32>>) -> (Vector3<f32>, f32) {
+    let mut min = positions[0];
+    let mut max = positions[0];
+    for p in positions {
+        min = Vector3{x: min.x.min(p.x), y: min.y.min(p.y), z: min.z.min(p.z)};
+        max = Vector3{x: max.x.max(p.x), y: max.y.max(p.y), z: max.z.max(p.z)};
+    }
+    let center = (min + max) / 2.0;
+    let radius = farthest_distance(center, positions);
+    // Keep the map from collapsing for a single point or an axis-aligned sliver.
+    (center, radius.max(0.001) * 1.01)
+}
+
+fn farthest_distance(from: Vector3<f32>, positions: &Vec<Vector3<f32>>) -> f32 {
+    let farthest = positions.iter()
+        .map(|p| (p - from).magnitude())
+        .fold(0.0, f32::max);
+    farthest.max(0.001) * 1.01
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small horizontal square at y = 1, facing up.
+    fn caster_transform() -> Matrix4<f32> {
+        Matrix4::from_translation(Vector3{x: 0.0, y: 1.0, z: 0.0}) * Matrix4::from_angle_x(Deg(-90.0))
+    }
+
+    #[test]
+    fn test_directional_shadow() {
+        let light = Light::directional_light(Vector3{x: 0.0, y: 1.0, z: 0.0});
+        let caster = Mesh::xy_face(0.5);
+        let map = ShadowMap::render(&light, &[(&caster, caster_transform())], ShadowSettings::new())
+            .unwrap();
+        let up = Vector3{x: 0.0, y: 1.0, z: 0.0};
+        assert_eq!(map.visibility(&Vector3{x: 0.0, y: 0.0, z: 0.0}, &up, &up), 0.0);
+        assert_eq!(map.visibility(&Vector3{x: 0.0, y: 1.0, z: 0.0}, &up, &up), 1.0);
+        assert_eq!(map.visibility(&Vector3{x: 2.0, y: 0.0, z: 0.0}, &up, &up), 1.0);
+    }
+
+    #[test]
+    fn test_point_light_shadow() {
+        let light = Light::point_light(Vector3{x: 0.0, y: 2.0, z: 0.0});
+        let caster = Mesh::xy_face(0.5);
+        let map = ShadowMap::render(&light, &[(&caster, caster_transform())], ShadowSettings::new())
+            .unwrap();
+        let up = Vector3{x: 0.0, y: 1.0, z: 0.0};
+        let under = Vector3{x: 0.0, y: 0.0, z: 0.0};
+        let to_light = light.incidence(&under).0;
+        assert_eq!(map.visibility(&under, &up, &to_light), 0.0);
+        let beside = Vector3{x: 3.0, y: 0.0, z: 0.0};
+        let to_light = light.incidence(&beside).0;
+        assert_eq!(map.visibility(&beside, &up, &to_light), 1.0);
+        // At the light itself there's no direction to it; any answer will do, but not a panic.
+        let at_light = Vector3{x: 0.0, y: 2.0, z: 0.0};
+        map.visibility(&at_light, &up, &light.incidence(&at_light).0);
+        map.visibility(&at_light, &up, &Vector3{x: ::std::f32::NAN, y: ::std::f32::NAN, z: ::std::f32::NAN});
+    }
+
+    #[test]
+    fn test_cube_faces() {
+        for (index, &direction) in CUBE_DIRECTIONS.iter().enumerate() {
+            assert_eq!(cube_face(direction * 2.0 + Vector3{x: 0.1, y: -0.2, z: 0.3}), index);
+        }
+    }
+}
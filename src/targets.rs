@@ -0,0 +1,58 @@
+use colors::*;
+use frame::Frame;
+use rasterizer::*;
+use textures::*;
+
+/// Color and depth buffers that `Renderer::render_to` draws into instead of the screen. The
+/// color can then be used as a texture in a later pass, e.g. for a mirror, a security camera's
+/// monitor or a post-processing effect, and the depth for custom shadow maps.
+pub struct RenderTarget {
+    pub(crate) rasterizer: Rasterizer,
+}
+
+impl RenderTarget {
+    pub fn new(width: u32, height: u32) -> Self {
+        RenderTarget{rasterizer: Rasterizer::create(width, height)}
+    }
+
+    pub fn with_antialiasing(width: u32, height: u32, antialiasing: Antialiasing) -> Self {
+        RenderTarget{rasterizer: Rasterizer::with_antialiasing(width, height, antialiasing)}
+    }
+
+    pub fn width(&self) -> usize {
+        return self.color().width();
+    }
+
+    pub fn height(&self) -> usize {
+        return self.color().height();
+    }
+
+    /// What was drawn by the last pass, with the top of the view in the first row.
+    pub fn color(&self) -> &Frame<Color> {
+        return self.rasterizer.get_color_buffer();
+    }
+
+    /// The depth of every sample drawn by the last pass, in NDC; see `Rasterizer::get_z_buffer`.
+    pub fn depth(&self) -> &Frame<f32> {
+        return self.rasterizer.get_z_buffer();
+    }
+
+    /// A mipmapped copy of the color, for `Renderer::set_texture`. Its first row, the top of the
+    /// view, is at v = 0, as with loaded images. This allocates and averages down a whole chain
+    /// of mip levels, so for a target that's redrawn every frame, make the texture once and
+    /// bring it up to date with `update_texture`.
+    pub fn to_texture(&self) -> Texture {
+        Texture::create(self.color().clone())
+    }
+
+    /// Copies the color into a texture made by `to_texture` (or `Texture::without_mipmaps`, to
+    /// skip rebuilding mip levels), reusing its memory.
+    pub fn update_texture(&self, texture: &mut Texture) {
+        texture.update(self.color());
+    }
+
+    pub fn clear(&mut self) {
+        self.rasterizer.clear();
+        self.rasterizer.resolve();
+    }
+}
@@ -1,49 +1,220 @@
 
 use frame::*;
-use sdl2::pixels::Color;
-use cgmath::BaseNum;
+use cgmath::{BaseNum, Vector2};
 use colors::*;
+use images::*;
+use std;
+use std::io::Read;
+use std::path::Path;
 
 pub struct Texture {
-    buffer: Frame<Color>,
+    // Level 0 is the full image; each following level halves it, down to 1x1.
+    levels: Vec<Frame<Color>>,
+    /// How texture coordinates outside 0..1 are handled, horizontally and vertically.
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    /// Returned for texels outside the texture under `WrapMode::ClampToBorder`.
+    pub border_color: FloatColor,
 }
 
 impl Texture {
     pub fn create(buffer: Frame<Color>) -> Self {
+        Self::from_levels(Self::mip_chain(buffer))
+    }
+
+    /// A texture with only the full-size image, which skips the work of averaging it down into
+    /// smaller levels. Mipmapped filter modes sample it like their non-mipmapped counterparts.
+    pub fn without_mipmaps(buffer: Frame<Color>) -> Self {
+        Self::from_levels(vec![buffer])
+    }
+
+    fn from_levels(levels: Vec<Frame<Color>>) -> Self {
         return Texture{
-            buffer,
+            levels,
+            wrap_u: WrapMode::ClampToEdge,
+            wrap_v: WrapMode::ClampToEdge,
+            border_color: FloatColor::from_argb(0.0, 0.0, 0.0, 0.0),
         };
     }
 
+    pub fn with_wrap_mode(mut self, wrap_u: WrapMode, wrap_v: WrapMode) -> Self {
+        self.wrap_u = wrap_u;
+        self.wrap_v = wrap_v;
+        self
+    }
+
+    pub fn with_border_color(mut self, border_color: FloatColor) -> Self {
+        self.border_color = border_color;
+        self
+    }
+
+    /// Loads a PNG, BMP, TGA or PPM file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ImageError> {
+        Ok(Self::create(Frame::load(path)?))
+    }
+
+    pub fn read<R: Read>(reader: &mut R, format: ImageFormat) -> Result<Self, ImageError> {
+        Ok(Self::create(Frame::read(reader, format)?))
+    }
+
+    /// Replaces the image, e.g. with the latest frame of a render target, keeping the wrap
+    /// modes. An image the same size as the old one is copied into the texture's existing
+    /// memory, and any mip levels are averaged down again in place.
+    pub fn update(&mut self, image: &Frame<Color>) {
+        let mipmapped = self.levels.len() > 1;
+        if image.width() != self.levels[0].width() || image.height() != self.levels[0].height() {
+            self.levels = if mipmapped { Self::mip_chain(image.clone()) } else { vec![image.clone()] };
+            return;
+        }
+        self.levels[0].cells.copy_from_slice(&image.cells);
+        for level in 1..self.levels.len() {
+            let (larger, smaller) = self.levels.split_at_mut(level);
+            Self::downsample_into(&larger[level - 1], &mut smaller[0]);
+        }
+    }
+
+    pub fn level_count(&self) -> usize {
+        return self.levels.len();
+    }
+
+    pub fn level(&self, level: usize) -> Option<&Frame<Color>> {
+        return self.levels.get(level);
+    }
+
+    /// Samples the full-size image; mipmapped filter modes behave like their non-mipmapped
+    /// counterparts.
     pub fn sample(&self, x: f32, y: f32, filter_mode: TextureFilterMode) -> Color {
-        let tx = Self::constrain(x * self.buffer.width() as f32, 0.0, self.buffer.width() as f32);
-        let ty = Self::constrain(y * self.buffer.height() as f32, 0.0, self.buffer.height() as f32);
+        self.sample_lod(x, y, 0.0, filter_mode)
+    }
+
+    /// Samples with the level of detail chosen from the screen-space derivatives of the texture
+    /// coordinates, i.e. how far they move for a one pixel step in x and in y.
+    pub fn sample_with_derivatives(
+        &self,
+        uv: Vector2<f32>,
+        ddx: Vector2<f32>,
+        ddy: Vector2<f32>,
+        filter_mode: TextureFilterMode,
+    ) -> Color {
+        self.sample_lod(uv.x, uv.y, self.level_of_detail(ddx, ddy), filter_mode)
+    }
+
+    pub fn level_of_detail(&self, ddx: Vector2<f32>, ddy: Vector2<f32>) -> f32 {
+        let size = Vector2{x: self.levels[0].width() as f32, y: self.levels[0].height() as f32};
+        let footprint_x = (ddx.x * size.x).hypot(ddx.y * size.y);
+        let footprint_y = (ddy.x * size.x).hypot(ddy.y * size.y);
+        // Non-finite derivatives (e.g. degenerate triangles) fall back to the base level.
+        footprint_x.max(footprint_y).log2().max(0.0)
+    }
+
+    pub fn sample_lod(&self, x: f32, y: f32, lod: f32, filter_mode: TextureFilterMode) -> Color {
+        let lod = Self::constrain(lod, 0.0, (self.levels.len() - 1) as f32);
         match filter_mode {
-            TextureFilterMode::NearestNeighbor => {
-                self.sample_safely(tx as i32, ty as i32)
-            },
-            TextureFilterMode::Bilinear => {
-                let txr = tx.round();
-                let tyr = ty.round();
-                let dx = (tx - txr) + 0.5;
-                let dy = (ty - tyr) + 0.5;
-                let c00 = self.sample_safely((txr - 1.0) as i32, (tyr - 1.0) as i32);
-                let c01 = self.sample_safely(txr as i32, (tyr - 1.0) as i32);
-                let c10 = self.sample_safely((txr - 1.0) as i32, tyr as i32);
-                let c11 = self.sample_safely(txr as i32, tyr as i32);
-                let combined = FloatColor::from_sdl_color(&c00) * (1.0 - dx) * (1.0 - dy)
-                    + FloatColor::from_sdl_color(&c01) * dx * (1.0 - dy)
-                    + FloatColor::from_sdl_color(&c10) * (1.0 - dx) * dy
-                    + FloatColor::from_sdl_color(&c11) * dx * dy;
-                combined.as_sdl_color()
+            TextureFilterMode::NearestNeighbor => self.sample_nearest(0, x, y),
+            TextureFilterMode::Bilinear => self.sample_bilinear(0, x, y).as_color(),
+            TextureFilterMode::NearestMipmap => self.sample_nearest(lod.round() as usize, x, y),
+            TextureFilterMode::Trilinear => {
+                let lower = lod.floor();
+                let t = lod - lower;
+                let lower_color = self.sample_bilinear(lower as usize, x, y);
+                if t == 0.0 {
+                    return lower_color.as_color();
+                }
+                let upper_color = self.sample_bilinear(lower as usize + 1, x, y);
+                (lower_color * (1.0 - t) + upper_color * t).as_color()
             },
         }
     }
 
-    fn sample_safely(&self, x: i32, y: i32) -> Color {
-        let sx = Self::constrain(x, 0, self.buffer.width() as i32 - 1);
-        let sy = Self::constrain(y, 0, self.buffer.height() as i32 - 1);
-        return self.buffer.at(sx as usize, sy as usize).unwrap();
+    fn sample_nearest(&self, level: usize, x: f32, y: f32) -> Color {
+        let buffer = &self.levels[level];
+        let tx = (x * buffer.width() as f32).floor();
+        let ty = (y * buffer.height() as f32).floor();
+        match self.wrapped(level, tx as i32, ty as i32) {
+            Some((sx, sy)) => buffer.at(sx, sy).unwrap(),
+            None => self.border_color.as_color(),
+        }
+    }
+
+    fn sample_bilinear(&self, level: usize, x: f32, y: f32) -> FloatColor {
+        let buffer = &self.levels[level];
+        let tx = x * buffer.width() as f32;
+        let ty = y * buffer.height() as f32;
+        let txr = tx.round();
+        let tyr = ty.round();
+        let dx = (tx - txr) + 0.5;
+        let dy = (ty - tyr) + 0.5;
+        let c00 = self.sample_safely(level, (txr - 1.0) as i32, (tyr - 1.0) as i32);
+        let c01 = self.sample_safely(level, txr as i32, (tyr - 1.0) as i32);
+        let c10 = self.sample_safely(level, (txr - 1.0) as i32, tyr as i32);
+        let c11 = self.sample_safely(level, txr as i32, tyr as i32);
+        c00 * (1.0 - dx) * (1.0 - dy)
+            + c01 * dx * (1.0 - dy)
+            + c10 * (1.0 - dx) * dy
+            + c11 * dx * dy
+    }
+
+    fn sample_safely(&self, level: usize, x: i32, y: i32) -> FloatColor {
+        match self.wrapped(level, x, y) {
+            Some((sx, sy)) => FloatColor::from_color(&self.levels[level].at(sx, sy).unwrap()),
+            None => self.border_color,
+        }
+    }
+
+    // Maps texel coordinates into the texture, or None if they fall on the border.
+    fn wrapped(&self, level: usize, x: i32, y: i32) -> Option<(usize, usize)> {
+        let buffer = &self.levels[level];
+        let sx = self.wrap_u.apply(x, buffer.width() as i32)?;
+        let sy = self.wrap_v.apply(y, buffer.height() as i32)?;
+        Some((sx as usize, sy as usize))
+    }
+
+    // Level 0 is `buffer`; each following level halves it, down to 1x1.
+    fn mip_chain(buffer: Frame<Color>) -> Vec<Frame<Color>> {
+        let mut levels = vec![buffer];
+        loop {
+            let next = {
+                let last = levels.last().unwrap();
+                if last.width() <= 1 && last.height() <= 1 {
+                    break;
+                }
+                let mut next = Frame::new(
+                    std::cmp::max(last.width() / 2, 1),
+                    std::cmp::max(last.height() / 2, 1),
+                    Color::RGBA(0, 0, 0, 0),
+                );
+                Self::downsample_into(last, &mut next);
+                next
+            };
+            levels.push(next);
+        }
+        levels
+    }
+
+    // Box filters each 2x2 block of `buffer` into one texel of `result`, which must be half its
+    // size, rounded down but at least 1. Odd dimensions repeat the last row or column.
+    fn downsample_into(buffer: &Frame<Color>, result: &mut Frame<Color>) {
+        for y in 0..result.height() {
+            for x in 0..result.width() {
+                let mut sums = [0u32; 4];
+                for &(dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = std::cmp::min(x * 2 + dx, buffer.width() - 1);
+                    let sy = std::cmp::min(y * 2 + dy, buffer.height() - 1);
+                    let color = buffer.at(sx, sy).unwrap();
+                    sums[0] += color.r as u32;
+                    sums[1] += color.g as u32;
+                    sums[2] += color.b as u32;
+                    sums[3] += color.a as u32;
+                }
+                let average = |sum: u32| ((sum + 2) / 4) as u8;
+                result.set(x, y, Color::RGBA(
+                    average(sums[0]),
+                    average(sums[1]),
+                    average(sums[2]),
+                    average(sums[3]),
+                ));
+            }
+        }
     }
 
     fn constrain<T>(x: T, lower: T, upper: T) -> T where T: BaseNum {
@@ -57,7 +228,122 @@ impl Texture {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    /// Repeats, flipping every other copy so that edges meet seamlessly.
+    MirroredRepeat,
+    ClampToEdge,
+    ClampToBorder,
+}
+
+impl WrapMode {
+    fn apply(&self, coordinate: i32, size: i32) -> Option<i32> {
+        match *self {
+            WrapMode::Repeat => Some(coordinate.rem_euclid(size)),
+            WrapMode::MirroredRepeat => {
+                let wrapped = coordinate.rem_euclid(2 * size);
+                Some(if wrapped >= size { 2 * size - 1 - wrapped } else { wrapped })
+            },
+            WrapMode::ClampToEdge => Some(Texture::constrain(coordinate, 0, size - 1)),
+            WrapMode::ClampToBorder => {
+                if coordinate < 0 || coordinate >= size { None } else { Some(coordinate) }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TextureFilterMode {
     NearestNeighbor,
     Bilinear,
+    /// Nearest texel from the nearest mip level.
+    NearestMipmap,
+    /// Bilinear samples from the two nearest mip levels, blended.
+    Trilinear,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(size: usize) -> Frame<Color> {
+        let mut frame = Frame::new(size, size, Color::RGB(0, 0, 0));
+        for y in 0..size {
+            for x in 0..size {
+                if (x + y) % 2 == 0 {
+                    frame.set(x, y, Color::RGB(255, 255, 255));
+                }
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn test_mip_chain() {
+        let texture = Texture::create(Frame::new(8, 2, Color::RGB(10, 20, 30)));
+        let sizes: Vec<(usize, usize)> = (0..texture.level_count())
+            .map(|i| (texture.level(i).unwrap().width(), texture.level(i).unwrap().height()))
+            .collect();
+        assert_eq!(sizes, vec![(8, 2), (4, 1), (2, 1), (1, 1)]);
+        assert_eq!(texture.level(3).unwrap().at(0, 0), Some(Color::RGB(10, 20, 30)));
+    }
+
+    #[test]
+    fn test_update_in_place() {
+        let mut texture = Texture::create(checkerboard(8)).with_wrap_mode(WrapMode::Repeat, WrapMode::Repeat);
+        let image = Frame::new(8, 8, Color::RGB(40, 80, 120));
+        texture.update(&image);
+        let fresh = Texture::create(image.clone());
+        assert_eq!(texture.level_count(), fresh.level_count());
+        for level in 0..texture.level_count() {
+            assert_eq!(texture.level(level).unwrap().cells(), fresh.level(level).unwrap().cells());
+        }
+        assert_eq!(texture.wrap_u, WrapMode::Repeat);
+
+        let mut flat = Texture::without_mipmaps(checkerboard(8));
+        assert_eq!(flat.level_count(), 1);
+        flat.update(&Frame::new(4, 2, Color::RGB(1, 2, 3)));
+        assert_eq!(flat.level_count(), 1);
+        assert_eq!(flat.sample(0.5, 0.5, TextureFilterMode::Trilinear), Color::RGB(1, 2, 3));
+        texture.update(&Frame::new(4, 2, Color::RGB(1, 2, 3)));
+        assert_eq!(texture.level_count(), 3);
+    }
+
+    #[test]
+    fn test_minified_checkerboard_averages() {
+        let texture = Texture::create(checkerboard(16));
+        let step = Vector2{x: 1.0, y: 0.0};
+        assert_eq!(texture.level_of_detail(step, step), 4.0);
+        let color = texture.sample_with_derivatives(
+            Vector2{x: 0.3, y: 0.6}, step, step, TextureFilterMode::Trilinear);
+        assert_eq!(color, Color::RGBA(128, 128, 128, 255));
+        let magnified = Vector2{x: 0.01, y: 0.0};
+        assert_eq!(texture.level_of_detail(magnified, magnified), 0.0);
+    }
+
+    #[test]
+    fn test_wrap_modes() {
+        let mut frame = Frame::new(4, 1, Color::RGB(0, 0, 0));
+        for x in 0..4 {
+            frame.set(x, 0, Color::RGB(x as u8, 0, 0));
+        }
+        let red_at = |texture: &Texture, u: f32| {
+            texture.sample(u, 0.5, TextureFilterMode::NearestNeighbor).r
+        };
+        let texture = Texture::create(frame.clone());
+        assert_eq!(red_at(&texture, 1.3), 3);
+        assert_eq!(red_at(&texture, -0.3), 0);
+        let texture = texture.with_wrap_mode(WrapMode::Repeat, WrapMode::Repeat);
+        assert_eq!(red_at(&texture, 1.3), 1);
+        assert_eq!(red_at(&texture, -0.3), 2);
+        let texture = texture.with_wrap_mode(WrapMode::MirroredRepeat, WrapMode::Repeat);
+        assert_eq!(red_at(&texture, 1.3), 2);
+        assert_eq!(red_at(&texture, -0.3), 1);
+        let texture = texture
+            .with_wrap_mode(WrapMode::ClampToBorder, WrapMode::Repeat)
+            .with_border_color(FloatColor::from_rgb(1.0, 0.0, 0.0));
+        assert_eq!(red_at(&texture, 1.3), 255);
+        assert_eq!(red_at(&texture, 0.3), 1);
+    }
 }
@@ -0,0 +1,125 @@
+use std;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+// What a worker is sent: its index and the function to call with it. The function's lifetime
+// is erased by `run`, which doesn't return until every worker is done with it.
+type Job = (usize, &'static (dyn Fn(usize) + Sync));
+
+/// Threads that are started once and then wait for work, so that splitting each draw across
+/// cores doesn't pay for starting and joining threads.
+pub struct ThreadPool {
+    workers: Vec<(Sender<Job>, JoinHandle<()>)>,
+    // Each worker reports whether its call panicked. Held for the whole of `run`, so calls from
+    // different threads don't mix up their reports.
+    done: Mutex<Receiver<bool>>,
+}
+
+impl ThreadPool {
+    /// A pool that runs work on `threads` threads, counting the one that calls `run`; 0 is
+    /// taken as 1, which starts no threads at all.
+    pub fn new(threads: usize) -> Self {
+        let (done_sender, done) = channel();
+        let workers = (1..threads)
+            .map(|_| {
+                let (sender, jobs) = channel::<Job>();
+                let done_sender = done_sender.clone();
+                let handle = thread::spawn(move || {
+                    for (index, job) in jobs {
+                        let panicked = panic::catch_unwind(AssertUnwindSafe(|| job(index))).is_err();
+                        if done_sender.send(panicked).is_err() {
+                            return;
+                        }
+                    }
+                });
+                (sender, handle)
+            })
+            .collect();
+        ThreadPool{workers, done: Mutex::new(done)}
+    }
+
+    pub fn threads(&self) -> usize {
+        return self.workers.len() + 1;
+    }
+
+    /// Calls `job` once on each of the pool's threads with a different index from 0 up to
+    /// `threads()`, the calling thread taking 0, and returns once every call has. If any of them
+    /// panics, so does this.
+    pub fn run(&self, job: &(dyn Fn(usize) + Sync)) {
+        let done = self.done.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Sound because the workers are waited on below, whatever happens, before `job` can go
+        // out of scope.
+        let erased: &'static (dyn Fn(usize) + Sync) = unsafe { std::mem::transmute(job) };
+        let mut started = 0;
+        for (index, worker) in self.workers.iter().enumerate() {
+            if worker.0.send((index + 1, erased)).is_ok() {
+                started += 1;
+            }
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| job(0)));
+        let mut panicked = false;
+        for _ in 0..started {
+            match done.recv() {
+                Ok(worker_panicked) => panicked |= worker_panicked,
+                // Every worker has exited, so none of them can be using `job`.
+                Err(_) => break,
+            }
+        }
+        drop(done);
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+        if panicked {
+            panic!("a thread pool worker panicked");
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        for (sender, handle) in self.workers.drain(..) {
+            // Closing the channel ends the worker's loop.
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_runs_on_every_thread() {
+        let pool = ThreadPool::new(4);
+        assert_eq!(pool.threads(), 4);
+        for _ in 0..3 {
+            let calls = Mutex::new(vec![]);
+            pool.run(&|index| calls.lock().unwrap().push(index));
+            let mut calls = calls.into_inner().unwrap();
+            calls.sort();
+            assert_eq!(calls, vec![0, 1, 2, 3]);
+        }
+        assert_eq!(ThreadPool::new(0).threads(), 1);
+    }
+
+    #[test]
+    fn test_worker_panics_reach_the_caller() {
+        let pool = ThreadPool::new(3);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.run(&|index| {
+            if index == 2 {
+                panic!("worker 2");
+            }
+        })));
+        assert!(result.is_err());
+        // The worker survives to take the next job.
+        let calls = AtomicUsize::new(0);
+        pool.run(&|_| {
+            calls.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+}